@@ -0,0 +1,184 @@
+use rocket::serde::{de::Error, Deserialize, Deserializer};
+
+use crate::db::RETENTION_HOURS;
+
+// Configuration of the API, extracted by Rocket from `Rocket.toml` and `ROCKET_*` env variables.
+// Every value has a default so that API can be started without any configuration
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AppConfig {
+    // Max difference in seconds between timestamp signed by host and server time
+    #[serde(default = "default_max_clock_skew")]
+    pub max_clock_skew: u64,
+    // Reject reports without `timestamp` and `nonce`, otherwise they are stored without replay protection
+    #[serde(default)]
+    pub require_nonce: bool,
+    // Max age in seconds of a report submitted via `/hosts/stats/batch`
+    #[serde(default = "default_max_batch_report_age")]
+    pub max_batch_report_age: u64,
+    // What to do with reports of hosts that are missing in `opsconsoledb.registrations`
+    #[serde(default)]
+    pub registration_policy: RegistrationPolicy,
+    // How often in seconds in-process index of registered hosts is reloaded, 0 disables the index
+    #[serde(default = "default_registration_refresh_interval")]
+    pub registration_refresh_interval: u64,
+    // Number of reports a single host can submit per hour, 0 disables rate limiting
+    #[serde(default = "default_rate_limit_per_hour")]
+    pub rate_limit_per_hour: u32,
+    // Number of reports a single host can submit at once before being limited
+    #[serde(default = "default_rate_limit_burst")]
+    pub rate_limit_burst: u32,
+    // Max number of reports waiting to be written to database, 0 writes each report right away
+    #[serde(default = "default_ingest_queue_capacity")]
+    pub ingest_queue_capacity: usize,
+    // Number of queued reports written to database with a single insert
+    #[serde(default = "default_ingest_batch_size")]
+    pub ingest_batch_size: usize,
+    // Max time in milliseconds a report waits in queue
+    #[serde(default = "default_ingest_flush_interval")]
+    pub ingest_flush_interval: u64,
+    // Store a new `holoport_status` document only when state of a host changes,
+    // otherwise refresh `lastSeen` of the previous one
+    #[serde(default)]
+    pub dedup_reports: bool,
+    // Expected time in seconds between two reports of a host, longer gaps count as downtime
+    #[serde(default = "default_uptime_report_interval")]
+    pub uptime_report_interval: u64,
+    // Windows in hours over which uptime of a host is computed from its reports
+    #[serde(
+        default = "default_uptime_windows",
+        deserialize_with = "deserialize_uptime_windows"
+    )]
+    pub uptime_windows: Vec<u64>,
+    // How often in seconds capacity of each network is recorded, 0 disables recording
+    #[serde(default = "default_capacity_snapshot_interval")]
+    pub capacity_snapshot_interval: u64,
+    // Tiers of network capacity, each one counts hosts that meet its criteria
+    #[serde(default = "default_capacity_tiers")]
+    pub capacity_tiers: Vec<CapacityTier>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct CapacityTier {
+    // Name of the tier in `Capacity`
+    pub name: String,
+    // Min uptime of a host, 0 - 1
+    pub min_uptime: f32,
+    // If set, only hosts of these models count
+    #[serde(default)]
+    pub holoport_models: Option<Vec<String>>,
+    // If set, only hosts in these holo networks count
+    #[serde(default)]
+    pub holo_networks: Option<Vec<String>>,
+}
+
+impl CapacityTier {
+    pub fn new(name: &str, min_uptime: f32) -> Self {
+        CapacityTier {
+            name: name.to_string(),
+            min_uptime,
+            holoport_models: None,
+            holo_networks: None,
+        }
+    }
+
+    /// Checks whether host with given uptime, model and network belongs to the tier.
+    /// Host with unknown model or network does not belong to tiers limited to some of them
+    pub fn includes(
+        &self,
+        uptime: f32,
+        holoport_model: Option<&str>,
+        holo_network: Option<&str>,
+    ) -> bool {
+        uptime >= self.min_uptime
+            && is_listed(&self.holoport_models, holoport_model)
+            && is_listed(&self.holo_networks, holo_network)
+    }
+}
+
+fn is_listed(list: &Option<Vec<String>>, value: Option<&str>) -> bool {
+    match list {
+        None => true,
+        Some(list) => value.is_some_and(|value| list.iter().any(|item| item == value)),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationPolicy {
+    // Reject reports of unregistered hosts
+    Enforce,
+    // Store reports of unregistered hosts, but mark them as unregistered
+    Tag,
+    // Do not look up registration at all
+    #[default]
+    Off,
+}
+
+fn default_max_clock_skew() -> u64 {
+    5 * 60
+}
+
+fn default_max_batch_report_age() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_registration_refresh_interval() -> u64 {
+    5 * 60
+}
+
+fn default_rate_limit_per_hour() -> u32 {
+    60
+}
+
+fn default_rate_limit_burst() -> u32 {
+    10
+}
+
+fn default_ingest_queue_capacity() -> usize {
+    10_000
+}
+
+fn default_ingest_batch_size() -> usize {
+    500
+}
+
+fn default_ingest_flush_interval() -> u64 {
+    1000
+}
+
+fn default_uptime_report_interval() -> u64 {
+    60 * 60
+}
+
+fn default_uptime_windows() -> Vec<u64> {
+    vec![24, 7 * 24]
+}
+
+// Reports older than retention of `holoport_status` are gone, so that longer windows
+// would count the missing history as downtime
+fn deserialize_uptime_windows<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<u64>, D::Error> {
+    let windows = Vec::<u64>::deserialize(deserializer)?;
+    match windows.iter().find(|&&hours| hours > RETENTION_HOURS) {
+        Some(hours) => Err(D::Error::custom(format!(
+            "uptime window of {} hours is longer than {} hours of report retention",
+            hours, RETENTION_HOURS
+        ))),
+        None => Ok(windows),
+    }
+}
+
+fn default_capacity_snapshot_interval() -> u64 {
+    60 * 60
+}
+
+fn default_capacity_tiers() -> Vec<CapacityTier> {
+    vec![
+        CapacityTier::new("read_only", 0.5),
+        CapacityTier::new("source_chain", 0.9),
+    ]
+}
@@ -1,20 +1,38 @@
-use ed25519_dalek::VerifyingKey;
+use ed25519_dalek::{VerifyingKey, PUBLIC_KEY_LENGTH};
+use holochain_conductor_api::AppStatusFilter;
+use holochain_types::app::InstalledAppId;
 use hpos_config_core::public_key::to_holochain_encoded_agent_key;
-use mongodb::bson::{self, doc, DateTime, Document};
-use mongodb::options::AggregateOptions;
+use mongodb::bson::{self, doc, oid::ObjectId, DateTime, Document};
+use mongodb::error::{BulkWriteFailure, ErrorKind};
+use mongodb::options::{
+    AggregateOptions, Collation, CollationStrength, FindOneOptions, FindOptions, InsertManyOptions,
+};
 use mongodb::{Client, Collection};
 use rocket::futures::TryStreamExt;
 use rocket::response::Debug;
 use rocket::State;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::env::var;
 use std::time::{Duration, SystemTime};
 
+use crate::config::{CapacityTier, RegistrationPolicy};
+use crate::dedup::ReportDedup;
+use crate::ingest::IngestQueue;
+use crate::registry::RegistrationIndex;
 use crate::types::{
-    ApiError, Capacity, Error400, Error404, HostRegistration, HostStats, Performance, Result,
-    Uptime, ZerotierMember,
+    ApiError, BatchReportResult, Capacity, CapacitySnapshot, Error400, Error404, HappEvent,
+    HostFilter, HostRegistration, HostRegistrationInfo, HostStats, HostStatsBatch, HostStatsRecord,
+    NetworkCapacity, Page, Performance, RegistrationStatus, RejectReason, Rejection, Result,
+    Uptime, VersionKey, ZerotierMember,
 };
 
+// Server error code of a write that violates a unique index
+const DUPLICATE_KEY: i32 = 11000;
+
+// How long `cleanup_database` keeps reports in `holoport_status`, in hours
+pub const RETENTION_HOURS: u64 = 14 * 24;
+
 const HOURS_TOO_LARGE: Error400 =
     Error400::Message("Hours specified is too large. Cutoff is earlier than start of unix epoch");
 
@@ -44,26 +62,40 @@ pub async fn ping_database(db: &Client) -> Result<String> {
 }
 
 // Delete from host_statistics.holoport_status documents with
-// timestamp field older than 14 days. Used for database cleanup
+// timestamp field older than `RETENTION_HOURS`. Used for database cleanup
 pub async fn cleanup_database(db: &Client) -> Result<String, ApiError> {
     let hp_status: Collection<HostStats> =
         db.database("host_statistics").collection("holoport_status");
 
-    let cutoff_ms = match get_cutoff_timestamp(14 * 24) {
+    let cutoff_ms = match get_cutoff_timestamp(RETENTION_HOURS) {
         Some(x) => x,
         None => return Err(ApiError::BadRequest(HOURS_TOO_LARGE)),
     };
 
+    // Documents refreshed by heartbeats of deduplicated reports are still current
     let val = doc! {
         "timestamp": {
           "$lt": cutoff_ms
+        },
+        "lastSeen": {
+          "$not": { "$gte": cutoff_ms }
         }
     };
 
-    match hp_status.delete_many(val, None).await {
+    let deleted = match hp_status.delete_many(val, None).await {
+        Ok(res) => res.deleted_count,
+        Err(e) => return Err(ApiError::Database(Debug(e))),
+    };
+
+    let happ_events: Collection<HappEvent> =
+        db.database("host_statistics").collection("happ_events");
+    match happ_events
+        .delete_many(doc! { "timestamp": { "$lt": cutoff_ms } }, None)
+        .await
+    {
         Ok(res) => Ok(format!(
-            "Deleted {} documents from holoport_status collection",
-            res.deleted_count
+            "Deleted {} documents from holoport_status collection and {} from happ_events collection",
+            deleted, res.deleted_count
         )),
         Err(e) => Err(ApiError::Database(Debug(e))),
     }
@@ -71,53 +103,218 @@ pub async fn cleanup_database(db: &Client) -> Result<String, ApiError> {
 
 // Find a value of uptime for host identified by its name in a collection `performance_summary`
 // Returns 404 if not found
-pub async fn host_uptime(name: String, db: &Client) -> Option<Uptime> {
+pub async fn host_uptime(name: String, db: &Client) -> Result<Option<Uptime>, ApiError> {
     let records: Collection<Performance> = db
         .database("host_statistics")
         .collection("performance_summary");
 
-    if let Some(host) = records
+    let host = records
         .find_one(Some(doc! {"name": name}), None)
         .await
-        .unwrap()
-    {
-        return Some(Uptime {
-            uptime: host.uptime,
-        });
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+    Ok(host.map(|host| Uptime {
+        uptime: host.uptime,
+    }))
+}
+
+// Calculate capacity of whole network and of each holo network
+// from all the records in `performance_summary` collection
+pub async fn network_capacity(
+    db: &Client,
+    tiers: &[CapacityTier],
+) -> Result<NetworkCapacity, ApiError> {
+    let mut total = Capacity::new(tiers);
+    let mut networks = BTreeMap::new();
+    for (holo_network, capacity) in host_capacities(db, tiers).await? {
+        total.merge(&capacity);
+        networks.insert(
+            holo_network.unwrap_or_else(|| "unknown".to_string()),
+            capacity,
+        );
     }
-    None
+    Ok(NetworkCapacity { total, networks })
+}
+
+// Calculate capacity of each holo network from all the records in `performance_summary` collection
+pub async fn network_capacity_snapshot(
+    db: &Client,
+    timestamp: i64,
+    tiers: &[CapacityTier],
+) -> Result<Vec<CapacitySnapshot>, ApiError> {
+    Ok(host_capacities(db, tiers)
+        .await?
+        .into_iter()
+        .map(|(holo_network, capacity)| CapacitySnapshot {
+            timestamp,
+            holo_network,
+            capacity,
+        })
+        .collect())
 }
 
-// Calculate network capacity from all the records in `performance_summary` collection
-pub async fn network_capacity(db: &Client) -> Result<Capacity> {
+// Network and model of a host are taken from its latest report in `holoport_status`
+async fn host_capacities(
+    db: &Client,
+    tiers: &[CapacityTier],
+) -> Result<HashMap<Option<String>, Capacity>, ApiError> {
+    // Look for reports as old as `cleanup_database` keeps them
+    let hosts: HashMap<String, (Option<String>, Option<String>)> =
+        get_hosts_stats(db, RETENTION_HOURS, &HostFilter::default())
+            .await?
+            .into_iter()
+            .map(|host| (host.holoport_id, (host.holo_network, host.holoport_model)))
+            .collect();
+
     let records: Collection<Performance> = db
         .database("host_statistics")
         .collection("performance_summary");
-    let cursor = records.find(None, None).await?;
+    let performances: Vec<Performance> = records
+        .find(None, None)
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
 
-    // cursor is a stream so it requires try_fold() from TryStreamExt
-    cursor
-        .try_fold(
-            Capacity {
-                total_hosts: 0,
-                read_only: 0,
-                source_chain: 0,
+    Ok(capacity_by_network(&performances, &hosts, tiers))
+}
+
+// Fold performance of hosts into capacity of the network each host belongs to.
+// `hosts` maps holoport_id to (holo_network, holoport_model) of a host
+pub fn capacity_by_network(
+    performances: &[Performance],
+    hosts: &HashMap<String, (Option<String>, Option<String>)>,
+    tiers: &[CapacityTier],
+) -> HashMap<Option<String>, Capacity> {
+    let mut capacities: HashMap<Option<String>, Capacity> = HashMap::new();
+    for performance in performances {
+        let (network, model) = hosts.get(&performance.name).cloned().unwrap_or_default();
+        capacities
+            .entry(network.clone())
+            .or_insert_with(|| Capacity::new(tiers))
+            .add_host(
+                performance.uptime,
+                model.as_deref(),
+                network.as_deref(),
+                tiers,
+            );
+    }
+    capacities
+}
+
+// Add snapshots to the collection `capacity_snapshots`
+pub async fn add_capacity_snapshots(
+    snapshots: Vec<CapacitySnapshot>,
+    db: &Client,
+) -> Result<(), ApiError> {
+    if snapshots.is_empty() {
+        return Ok(());
+    }
+    let capacity_snapshots: Collection<CapacitySnapshot> = db
+        .database("host_statistics")
+        .collection("capacity_snapshots");
+    match capacity_snapshots.insert_many(snapshots, None).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ApiError::Database(Debug(e))),
+    }
+}
+
+// Return capacity snapshots taken between <from> and <to> (unix seconds) sorted by time.
+// With <interval> (seconds) only the last snapshot of each network in each interval is returned
+pub async fn get_capacity_history(
+    from: i64,
+    to: i64,
+    interval: Option<i64>,
+    db: &Client,
+) -> Result<Vec<CapacitySnapshot>, ApiError> {
+    let capacity_snapshots: Collection<CapacitySnapshot> = db
+        .database("host_statistics")
+        .collection("capacity_snapshots");
+
+    let mut pipeline = vec![
+        doc! {
+            "$match": {
+                "timestamp": {"$gte": from, "$lte": to}
+            }
+        },
+        doc! {
+            "$sort": {
+                "timestamp": 1
+            }
+        },
+    ];
+    if let Some(interval) = interval.filter(|interval| *interval > 0) {
+        pipeline.extend([
+            doc! {
+                "$group": {
+                    "_id": {
+                        "holo_network": "$holo_network",
+                        "interval": {"$subtract": ["$timestamp", {"$mod": ["$timestamp", interval]}]}
+                    },
+                    "timestamp": {"$last": "$timestamp"},
+                    "capacity": {"$last": "$capacity"}
+                }
             },
-            |mut total_capacity, performance| async move {
-                total_capacity.add_host(performance.uptime);
-                Ok(total_capacity)
+            doc! {
+                "$project": {
+                    "_id": 0,
+                    "timestamp": 1,
+                    "holo_network": "$_id.holo_network",
+                    "capacity": 1
+                }
             },
-        )
+            doc! {
+                "$sort": {
+                    "timestamp": 1
+                }
+            },
+        ]);
+    }
+
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+
+    capacity_snapshots
+        .aggregate(pipeline, Some(options))
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_filter_map(|snapshot| async { Ok(Some(bson::from_document(snapshot)?)) })
+        .try_collect()
         .await
         .map_err(Debug)
+        .map_err(ApiError::Database)
 }
 
 pub async fn get_zerotier_members(db: &Client) -> Result<Vec<ZerotierMember>, ApiError> {
+    find_zerotier_members(None, db).await
+}
+
+// Return ZeroTier members that have IP <zerotier_ip> or are named <holoport_id>
+pub async fn get_host_zerotier_members(
+    holoport_id: &str,
+    zerotier_ip: Option<&str>,
+    db: &Client,
+) -> Result<Vec<ZerotierMember>, ApiError> {
+    let mut conditions = vec![doc! { "name": holoport_id }];
+    if let Some(zerotier_ip) = zerotier_ip {
+        conditions.push(doc! { "zerotierIp": zerotier_ip });
+    }
+    find_zerotier_members(Some(doc! { "$match": { "$or": conditions } }), db).await
+}
+
+// Return authorized members from `latest_raw_snap` that match <filter> stage
+async fn find_zerotier_members(
+    filter: Option<Document>,
+    db: &Client,
+) -> Result<Vec<ZerotierMember>, ApiError> {
     let zerotier_snapshot: Collection<ZerotierMember> =
         db.database("host_statistics").collection("latest_raw_snap");
 
     // Use aggregation pipeline to extract only relevant fields from database
-    let pipeline = vec![
+    let mut pipeline = vec![
         doc! {
             "$match": {
               "config.authorized": true
@@ -134,6 +331,7 @@ pub async fn get_zerotier_members(db: &Client) -> Result<Vec<ZerotierMember>, Ap
               }
         },
     ];
+    pipeline.extend(filter);
 
     let options = AggregateOptions::builder().allow_disk_use(true).build();
 
@@ -152,8 +350,12 @@ pub async fn get_zerotier_members(db: &Client) -> Result<Vec<ZerotierMember>, Ap
 }
 
 // Return the most recent record for hosts stored in `holoport_status` collection
-// Ignores records older than <cutoff> hours
-pub async fn get_hosts_stats(db: &Client, cutoff: u64) -> Result<Vec<HostStats>, ApiError> {
+// Ignores records older than <cutoff> hours and hosts whose most recent record does not match <filter>
+pub async fn get_hosts_stats(
+    db: &Client,
+    cutoff: u64,
+    filter: &HostFilter,
+) -> Result<Vec<HostStats>, ApiError> {
     let cutoff_ms = match get_cutoff_timestamp(cutoff) {
         Some(x) => x,
         None => return Err(ApiError::BadRequest(HOURS_TOO_LARGE)),
@@ -162,11 +364,15 @@ pub async fn get_hosts_stats(db: &Client, cutoff: u64) -> Result<Vec<HostStats>,
     let hp_status: Collection<HostStats> =
         db.database("host_statistics").collection("holoport_status");
 
-    let pipeline = vec![
+    let mut pipeline = vec![
         doc! {
-            // get entries within last <cutoff> hours
+            // get entries within last <cutoff> hours, including older ones
+            // still confirmed by heartbeats of deduplicated reports
             "$match": {
-                "timestamp": {"$gte": cutoff_ms}
+                "$or": [
+                    {"timestamp": {"$gte": cutoff_ms}},
+                    {"lastSeen": {"$gte": cutoff_ms}}
+                ]
             }
         },
         doc! {
@@ -186,9 +392,20 @@ pub async fn get_hosts_stats(db: &Client, cutoff: u64) -> Result<Vec<HostStats>,
                 "ztIp": {"$first": "$ztIp"},
                 "wanIp": {"$first": "$wanIp"},
                 "timestamp": {"$first": "$timestamp"},
+                "lastSeen": {"$first": "$lastSeen"},
                 "hposAppList": {"$first": "$hposAppList"},
                 "channelVersion": {"$first": "$channelVersion"},
                 "hposVersion": {"$first": "$hposVersion"},
+                "registrationStatus": {"$first": "$registrationStatus"},
+                "schemaVersion": {"$first": "$schemaVersion"},
+                "cpuLoad": {"$first": "$cpuLoad"},
+                "memoryTotal": {"$first": "$memoryTotal"},
+                "memoryUsed": {"$first": "$memoryUsed"},
+                "diskTotal": {"$first": "$diskTotal"},
+                "diskUsed": {"$first": "$diskUsed"},
+                "uptimeSeconds": {"$first": "$uptimeSeconds"},
+                "kernelVersion": {"$first": "$kernelVersion"},
+                "holochainVersion": {"$first": "$holochainVersion"},
             }
         },
         doc! {
@@ -202,13 +419,27 @@ pub async fn get_hosts_stats(db: &Client, cutoff: u64) -> Result<Vec<HostStats>,
                 "sshStatus": "$sshStatus",
                 "ztIp": "$ztIp",
                 "wanIp": "$wanIp",
-                "timestamp":"$timestamp",
+                // time of the last report, whether it was stored or only refreshed `lastSeen`
+                "timestamp": {"$max": ["$timestamp", "$lastSeen"]},
                 "hposAppList": "$hposAppList",
                 "channelVersion": "$channelVersion",
                 "hposVersion": "$hposVersion",
+                "registrationStatus": "$registrationStatus",
+                "schemaVersion": "$schemaVersion",
+                "cpuLoad": "$cpuLoad",
+                "memoryTotal": "$memoryTotal",
+                "memoryUsed": "$memoryUsed",
+                "diskTotal": "$diskTotal",
+                "diskUsed": "$diskUsed",
+                "uptimeSeconds": "$uptimeSeconds",
+                "kernelVersion": "$kernelVersion",
+                "holochainVersion": "$holochainVersion",
               }
         },
     ];
+    if let Some(stage) = host_filter_stage(filter) {
+        pipeline.push(stage);
+    }
 
     let options = AggregateOptions::builder().allow_disk_use(true).build();
 
@@ -226,6 +457,435 @@ pub async fn get_hosts_stats(db: &Client, cutoff: u64) -> Result<Vec<HostStats>,
         .map_err(ApiError::Database)
 }
 
+// Build `$match` stage of `get_hosts_stats` out of the fields of <filter> that are stored in `holoport_status`.
+// `has_errors` is known only once hosts are merged with zerotier members, see `handlers::filter_hosts`
+pub fn host_filter_stage(filter: &HostFilter) -> Option<Document> {
+    let mut conditions = Document::new();
+    if let Some(holo_network) = &filter.holo_network {
+        conditions.insert("holoNetwork", holo_network);
+    }
+    if let Some(channel) = &filter.channel {
+        conditions.insert("channel", channel);
+    }
+    if let Some(holoport_model) = &filter.holoport_model {
+        conditions.insert("holoportModel", holoport_model);
+    }
+    if let Some(ssh_status) = filter.ssh_status {
+        conditions.insert("sshStatus", ssh_status);
+    }
+    if let Some(hpos_version) = &filter.hpos_version {
+        conditions.insert("hposVersion", hpos_version);
+    }
+    // App ids with `.` or a leading `$` cannot be used in a field path
+    if let Some(app_id) = &filter.running_app {
+        if !app_id.contains('.') && !app_id.starts_with('$') {
+            if let Ok(running) = bson::to_bson(&AppStatusFilter::Running) {
+                conditions.insert(format!("hposAppList.{}", app_id), running);
+            }
+        }
+    }
+
+    if conditions.is_empty() {
+        return None;
+    }
+    Some(doc! { "$match": conditions })
+}
+
+// Return timestamp of the first report of each combination of hposVersion, channelVersion and channel
+// stored in `holoport_status`
+pub async fn get_versions_first_seen(db: &Client) -> Result<HashMap<VersionKey, i64>, ApiError> {
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
+
+    let pipeline = vec![doc! {
+        "$group": {
+            "_id": {
+                "hposVersion": "$hposVersion",
+                "channelVersion": "$channelVersion",
+                "channel": "$channel"
+            },
+            "firstSeen": {"$min": "$timestamp"}
+        }
+    }];
+
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+
+    let records: Vec<Document> = hp_status
+        .aggregate(pipeline, Some(options))
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+
+    Ok(records
+        .iter()
+        .filter_map(|record| {
+            let key = record.get_document("_id").ok()?;
+            let version = |field| key.get_str(field).ok().map(String::from);
+            Some((
+                (
+                    version("hposVersion"),
+                    version("channelVersion"),
+                    version("channel"),
+                ),
+                record.get_i64("firstSeen").ok()?,
+            ))
+        })
+        .collect())
+}
+
+// Return for each host pending reboot (latest report has hposVersion different than channelVersion)
+// timestamp of its first report after the last one with matching versions.
+// Only reports still stored in `holoport_status` of hosts listed in `holoport_ids` are considered
+pub async fn get_pending_since(
+    holoport_ids: &[String],
+    db: &Client,
+) -> Result<HashMap<String, i64>, ApiError> {
+    if holoport_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
+
+    let pipeline = vec![
+        doc! {
+            "$match": {
+                "holoportId": {"$in": holoport_ids}
+            }
+        },
+        doc! {
+            "$sort": {
+                "timestamp": -1
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": "$holoportId",
+                "hposVersion": {"$first": "$hposVersion"},
+                "channelVersion": {"$first": "$channelVersion"},
+                // $max ignores nulls, so this is the last report with matching versions
+                "lastInSync": {"$max": {"$cond": [
+                    {"$eq": ["$hposVersion", "$channelVersion"]},
+                    {"$max": ["$timestamp", "$lastSeen"]},
+                    null
+                ]}}
+            }
+        },
+        doc! {
+            "$match": {
+                "hposVersion": {"$type": "string"},
+                "channelVersion": {"$type": "string"},
+                "$expr": {"$ne": ["$hposVersion", "$channelVersion"]}
+            }
+        },
+        doc! {
+            // Every report after the last one in sync was pending reboot
+            "$lookup": {
+                "from": "holoport_status",
+                "let": {"holoportId": "$_id", "lastInSync": {"$ifNull": ["$lastInSync", 0]}},
+                "pipeline": [
+                    {"$match": {"$expr": {"$and": [
+                        {"$eq": ["$holoportId", "$$holoportId"]},
+                        {"$gt": ["$timestamp", "$$lastInSync"]}
+                    ]}}},
+                    {"$group": {"_id": null, "pendingSince": {"$min": "$timestamp"}}}
+                ],
+                "as": "pending"
+            }
+        },
+        doc! {
+            "$project": {
+                "pendingSince": {"$arrayElemAt": ["$pending.pendingSince", 0]}
+            }
+        },
+    ];
+
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+
+    let records: Vec<Document> = hp_status
+        .aggregate(pipeline, Some(options))
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+
+    Ok(records
+        .iter()
+        .filter_map(|record| {
+            Some((
+                record.get_str("_id").ok()?.to_string(),
+                record.get_i64("pendingSince").ok()?,
+            ))
+        })
+        .collect())
+}
+
+// Return timestamp and app list of the latest report of a host made before <before>
+pub async fn get_previous_app_list(
+    holoport_id: &str,
+    before: i64,
+    db: &Client,
+) -> Result<Option<(i64, HashMap<InstalledAppId, AppStatusFilter>)>, ApiError> {
+    let hp_status: Collection<HostStats> =
+        db.database("host_statistics").collection("holoport_status");
+
+    let filter = doc! {
+        "holoportId": holoport_id,
+        "timestamp": {"$lt": before},
+        "hposAppList": {"$type": "object"}
+    };
+    let options = FindOneOptions::builder()
+        .sort(doc! { "timestamp": -1 })
+        .projection(doc! { "holoportId": 1, "timestamp": 1, "hposAppList": 1 })
+        .build();
+
+    match hp_status.find_one(filter, options).await {
+        Ok(stats) => Ok(stats.and_then(|stats| Some((stats.timestamp?, stats.hpos_app_list?)))),
+        Err(e) => Err(ApiError::Database(Debug(e))),
+    }
+}
+
+// Add transitions of apps to the collection `happ_events`
+pub async fn add_happ_events(events: Vec<HappEvent>, db: &Client) -> Result<(), ApiError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let happ_events: Collection<Document> =
+        db.database("host_statistics").collection("happ_events");
+
+    let mut docs = Vec::with_capacity(events.len());
+    for event in events {
+        let mut val = bson::to_document(&event).map_err(|e| ApiError::Database(Debug(e.into())))?;
+        val.insert("dateCreated", DateTime::now()); // Field of bson type Date required for TTL index
+        docs.push(val);
+    }
+    match happ_events.insert_many(docs, None).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ApiError::Database(Debug(e))),
+    }
+}
+
+// Return at most <limit> transitions of app <app_id> on host <holoport_id>, newest first
+pub async fn get_happ_events(
+    holoport_id: String,
+    app_id: String,
+    limit: Option<i64>,
+    db: &Client,
+) -> Result<Vec<HappEvent>, ApiError> {
+    let filter = doc! { "holoportId": holoport_id, "appId": app_id };
+    find_happ_events(filter, limit, db).await
+}
+
+// Return at most <limit> transitions of apps on any host to status paused or disabled
+// within last <cutoff> hours, newest first
+pub async fn get_happ_failures(
+    cutoff: u64,
+    limit: Option<i64>,
+    db: &Client,
+) -> Result<Vec<HappEvent>, ApiError> {
+    let cutoff_ms = match get_cutoff_timestamp(cutoff) {
+        Some(x) => x,
+        None => return Err(ApiError::BadRequest(HOURS_TOO_LARGE)),
+    };
+    let failed = [AppStatusFilter::Paused, AppStatusFilter::Disabled]
+        .iter()
+        .map(bson::to_bson)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::Database(Debug(e.into())))?;
+
+    let filter = doc! { "to": {"$in": failed}, "timestamp": {"$gte": cutoff_ms} };
+    find_happ_events(filter, limit, db).await
+}
+
+async fn find_happ_events(
+    filter: Document,
+    limit: Option<i64>,
+    db: &Client,
+) -> Result<Vec<HappEvent>, ApiError> {
+    let happ_events: Collection<HappEvent> =
+        db.database("host_statistics").collection("happ_events");
+
+    let options = FindOptions::builder()
+        .sort(doc! { "timestamp": -1 })
+        .limit(
+            limit
+                .unwrap_or(HISTORY_DEFAULT_LIMIT)
+                .clamp(1, HISTORY_MAX_LIMIT),
+        )
+        .build();
+
+    happ_events
+        .find(filter, options)
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)
+}
+
+// Return (timestamp, lastSeen) of all reports of a host made within last <cutoff> hours,
+// sorted by timestamp. lastSeen equals timestamp for reports that were not deduplicated
+pub async fn get_report_times(
+    holoport_id: &str,
+    cutoff: u64,
+    db: &Client,
+) -> Result<Vec<(i64, i64)>, ApiError> {
+    let cutoff_ms = match get_cutoff_timestamp(cutoff) {
+        Some(x) => x,
+        None => return Err(ApiError::BadRequest(HOURS_TOO_LARGE)),
+    };
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
+
+    let filter = doc! {
+        "holoportId": holoport_id,
+        "$or": [
+            {"timestamp": {"$gte": cutoff_ms}},
+            {"lastSeen": {"$gte": cutoff_ms}}
+        ]
+    };
+    let options = FindOptions::builder()
+        .sort(doc! { "timestamp": 1 })
+        .projection(doc! { "_id": 0, "timestamp": 1, "lastSeen": 1 })
+        .build();
+
+    hp_status
+        .find(filter, options)
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_filter_map(|report| async move {
+            Ok(report
+                .get_i64("timestamp")
+                .ok()
+                .map(|timestamp| (timestamp, report.get_i64("lastSeen").unwrap_or(timestamp))))
+        })
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)
+}
+
+// Default and max number of records in a single page of host history and of hApp events
+pub const HISTORY_DEFAULT_LIMIT: i64 = 100;
+pub const HISTORY_MAX_LIMIT: i64 = 1000;
+
+// Return records of a single host stored in `holoport_status`, newest first.
+// Records are limited to ones reported between <from> and <to> (unix seconds), a deduplicated record
+// is included also when it was reported earlier but its `lastSeen` falls within the range.
+// Next page starts after record identified by <cursor> returned with the previous page
+pub async fn get_host_history(
+    holoport_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<i64>,
+    cursor: Option<&str>,
+    db: &Client,
+) -> Result<Page<Vec<HostStatsRecord>>, ApiError> {
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
+
+    let limit = limit
+        .unwrap_or(HISTORY_DEFAULT_LIMIT)
+        .clamp(1, HISTORY_MAX_LIMIT);
+
+    let mut conditions = vec![doc! { "holoportId": holoport_id }];
+    if let Some(from) = from {
+        conditions.push(doc! {
+            "$or": [
+                {"timestamp": {"$gte": from}},
+                {"lastSeen": {"$gte": from}}
+            ]
+        });
+    }
+    if let Some(to) = to {
+        conditions.push(doc! { "timestamp": {"$lte": to} });
+    }
+    let total = hp_status
+        .count_documents(doc! { "$and": conditions.clone() }, None)
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+    if let Some(cursor) = cursor {
+        let (timestamp, id) = parse_history_cursor(cursor)?;
+        conditions.push(doc! {
+            "$or": [
+                {"timestamp": {"$lt": timestamp}},
+                {"timestamp": timestamp, "_id": {"$lt": id}}
+            ]
+        });
+    }
+
+    // _id breaks ties between records with the same timestamp, so that pages do not overlap.
+    // One record more than requested tells if there is a next page
+    let options = FindOptions::builder()
+        .sort(doc! { "timestamp": -1, "_id": -1 })
+        .limit(limit + 1)
+        .projection(doc! { "dateCreated": 0 })
+        .build();
+
+    let records: Vec<Document> = hp_status
+        .find(doc! { "$and": conditions }, options)
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+
+    let has_next = records.len() as i64 > limit;
+    let mut reports = Vec::with_capacity(records.len());
+    let mut next_cursor = None;
+    for record in records.into_iter().take(limit as usize) {
+        next_cursor = match (record.get_i64("timestamp"), record.get_object_id("_id")) {
+            (Ok(timestamp), Ok(id)) => Some(history_cursor(timestamp, id)),
+            _ => None,
+        };
+        let report: HostStatsRecord =
+            bson::from_document(record).map_err(|e| ApiError::Database(Debug(e.into())))?;
+        reports.push(report);
+    }
+
+    Ok(Page {
+        inner: reports,
+        total: total as usize,
+        next_cursor: next_cursor.filter(|_| has_next),
+    })
+}
+
+// Cursor pointing at a record of `holoport_status`, in the order records are returned from history
+pub fn history_cursor(timestamp: i64, id: ObjectId) -> String {
+    format!("{}_{}", timestamp, id.to_hex())
+}
+
+pub fn parse_history_cursor(cursor: &str) -> Result<(i64, ObjectId), ApiError> {
+    cursor
+        .split_once('_')
+        .and_then(|(timestamp, id)| Some((timestamp.parse().ok()?, ObjectId::parse_str(id).ok()?)))
+        .ok_or(ApiError::BadRequest(Error400::Message(
+            "Cursor is invalid, use `X-Next-Cursor` header returned with the previous page",
+        )))
+}
+
+// Current unix timestamp in seconds, the same unit as `timestamp` field in `holoport_status`
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|t| i64::try_from(t.as_secs()).ok().unwrap_or(0))
+        .unwrap_or(0)
+}
+
 // Helper function to get cutoff timestamp for filter
 // We use u64 for hours because otherwise we have to recast as u64 in the function, and 4 bytes isn't a big deal here
 // Returns None if hours is too large and causes negative timestamp (propagates .checked_sub() which does the same)
@@ -245,10 +905,8 @@ fn get_cutoff_timestamp(hours: u64) -> Option<i64> {
     )
 }
 
-// Add values to the collection `holoport_status`
-pub async fn add_holoport_status(hs: HostStats, db: &Client) -> Result<(), ApiError> {
-    let hp_status: Collection<Document> =
-        db.database("host_statistics").collection("holoport_status");
+// Build a document of collection `holoport_status` out of reported HostStats
+fn holoport_status_doc(hs: HostStats) -> Result<Document, ApiError> {
     let hpos_app_list =
         match bson::to_bson(&hs.hpos_app_list) {
             Ok(bson) => bson,
@@ -258,7 +916,8 @@ pub async fn add_holoport_status(hs: HostStats, db: &Client) -> Result<(), ApiEr
             )))),
         };
 
-    let val = doc! {
+    Ok(doc! {
+        "_id": ObjectId::new(),
         "holoNetwork": hs.holo_network,
         "channel": hs.channel,
         "holoportModel": hs.holoport_model,
@@ -270,81 +929,388 @@ pub async fn add_holoport_status(hs: HostStats, db: &Client) -> Result<(), ApiEr
         "hposAppList": hpos_app_list,
         "channelVersion": hs.channel_version,
         "hposVersion": hs.hpos_version,
+        "registrationStatus": bson::to_bson(&hs.registration_status).unwrap_or(bson::Bson::Null),
+        "schemaVersion": i32::from(hs.schema_version.unwrap_or(1)),
+        "cpuLoad": hs.cpu_load,
+        "memoryTotal": hs.memory_total,
+        "memoryUsed": hs.memory_used,
+        "diskTotal": hs.disk_total,
+        "diskUsed": hs.disk_used,
+        "uptimeSeconds": hs.uptime_seconds,
+        "kernelVersion": hs.kernel_version,
+        "holochainVersion": hs.holochain_version,
         "dateCreated": DateTime::now(), // Field of bson type Date required for TTL index
-    };
+    })
+}
+
+// Add values to the collection `holoport_status`
+pub async fn add_holoport_status(val: Document, db: &Client) -> Result<(), ApiError> {
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
     match hp_status.insert_one(val, None).await {
         Ok(_) => Ok(()),
         Err(e) => Err(ApiError::Database(Debug(e))),
     }
 }
 
+// Fields of a stored status document updated by later reports with the same state
+fn gauge_fields(hs: &HostStats) -> Document {
+    doc! {
+        "cpuLoad": hs.cpu_load,
+        "memoryUsed": hs.memory_used,
+        "diskUsed": hs.disk_used,
+        "uptimeSeconds": hs.uptime_seconds,
+    }
+}
+
+// Refresh document of `holoport_status` with a report that did not change state of the host.
+// Gauges that change with every report are updated too, so that the document shows latest values,
+// unless the report is older than the latest one already merged into the document.
+// Returns false if there is no such document
+pub async fn refresh_holoport_status(
+    id: ObjectId,
+    hs: &HostStats,
+    db: &Client,
+) -> Result<bool, ApiError> {
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
+
+    let mut fields = gauge_fields(hs);
+    fields.insert("lastSeen", hs.timestamp);
+    // Keep document alive for TTL index while host reports the same state
+    fields.insert("dateCreated", DateTime::now());
+    let newer = doc! {
+        "_id": id,
+        "$or": [
+            {"lastSeen": {"$lte": hs.timestamp}},
+            {"lastSeen": {"$exists": false}, "timestamp": {"$lte": hs.timestamp}}
+        ]
+    };
+    let res = hp_status
+        .update_one(newer, doc! { "$set": fields }, None)
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+    if res.matched_count > 0 {
+        return Ok(true);
+    }
+
+    // Delayed report only keeps the document alive, it cannot move `lastSeen` back
+    let update = doc! {
+        "$max": {"lastSeen": hs.timestamp},
+        "$set": {"dateCreated": DateTime::now()},
+    };
+    match hp_status.update_one(doc! {"_id": id}, update, None).await {
+        Ok(res) => Ok(res.matched_count > 0),
+        Err(e) => Err(ApiError::Database(Debug(e))),
+    }
+}
+
+// Add many values to the collection `holoport_status` in a single round trip.
+// Insert can be retried, documents stored by an earlier attempt are skipped by their _id
+pub async fn add_holoport_statuses(docs: &[Document], db: &Client) -> Result<(), ApiError> {
+    if docs.is_empty() {
+        return Ok(());
+    }
+    let hp_status: Collection<Document> =
+        db.database("host_statistics").collection("holoport_status");
+    let options = InsertManyOptions::builder().ordered(false).build();
+    match hp_status.insert_many(docs, options).await {
+        Ok(_) => Ok(()),
+        Err(e) if only_duplicates(&e) => Ok(()),
+        Err(e) => Err(ApiError::Database(Debug(e))),
+    }
+}
+
+// Insert failed only on documents that are already stored
+fn only_duplicates(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::BulkWrite(BulkWriteFailure {
+            write_errors: Some(errors),
+            write_concern_error: None,
+            ..
+        }) => errors.iter().all(|error| error.code == DUPLICATE_KEY),
+        _ => false,
+    }
+}
+
 /// Ops Console DB:
-/// Find registration values for host identified in the `opsconsoledb` collection `registration`
-/// and determine whether the provided host pub key exists within record
+/// Find registration record in the `opsconsoledb` collection `registrations`
+/// with the provided host pub key among its agent pub keys
 pub async fn verify_host(pub_key: String, db: &Client) -> Result<(), ApiError> {
     let records: Collection<HostRegistration> =
         db.database("opsconsoledb").collection("registrations");
 
-    let mut host_registrations = match records.find(None, None).await {
-        Ok(cursor) => cursor,
-        Err(e) => return Err(ApiError::Database(Debug(e))),
-    };
+    let filter = doc! { "registrationCode.agentPubKeys.pubKey": &pub_key };
+
+    match records.find_one(filter, None).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ApiError::MissingRecord(Error404::Info(format!(
+            "No host found with provided public key. {:?}",
+            pub_key
+        )))),
+        Err(e) => Err(ApiError::Database(Debug(e))),
+    }
+}
+
+/// Ops Console DB:
+/// Return agent pub keys of all registrations in the collection `registrations`
+/// made with `email`, compared case insensitively
+pub async fn get_registered_pub_keys(
+    email: &str,
+    db: &Client,
+) -> Result<HashSet<String>, ApiError> {
+    let records: Collection<Document> = db.database("opsconsoledb").collection("registrations");
+
+    let pipeline = vec![
+        doc! { "$match": { "email": email } },
+        doc! { "$unwind": "$registrationCode" },
+        doc! { "$unwind": "$registrationCode.agentPubKeys" },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "pubKey": "$registrationCode.agentPubKeys.pubKey"
+            }
+        },
+    ];
 
-    let mut host_collection = Vec::new();
+    let collation = Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build();
+    let options = AggregateOptions::builder().collation(collation).build();
 
-    while let Some(hr) = host_registrations
-        .try_next()
+    records
+        .aggregate(pipeline, Some(options))
         .await
         .map_err(Debug)
         .map_err(ApiError::Database)?
-    {
-        host_collection.push(hr);
-    }
+        .try_filter_map(|key| async move { Ok(key.get_str("pubKey").ok().map(String::from)) })
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)
+}
+
+/// Ops Console DB:
+/// Find registration of a host with the provided holoport id among its agent pub keys
+/// in the collection `registrations`. Personal data of the registrant is read only if `include_pii` is set
+pub async fn get_host_registration(
+    holoport_id: &str,
+    include_pii: bool,
+    db: &Client,
+) -> Result<HostRegistrationInfo, ApiError> {
+    let pub_key = to_holochain_encoded_agent_key(&decode_pubkey(holoport_id)?);
+    let records: Collection<Document> = db.database("opsconsoledb").collection("registrations");
 
-    if let Some(found) = host_collection.iter().find_map(|hr| {
-        Some(
-            hr.registration_code
-                .iter()
-                .any(|r| r.agent_pub_keys.iter().any(|keys| keys.pub_key == pub_key)),
+    let projection = match include_pii {
+        true => doc! { "givenNames": 1, "lastName": 1, "email": 1 },
+        false => doc! { "_id": 1 },
+    };
+    let options = FindOneOptions::builder().projection(projection).build();
+
+    let record = records
+        .find_one(
+            doc! { "registrationCode.agentPubKeys.pubKey": &pub_key },
+            options,
         )
-    }) {
-        if found {
-            return Ok(());
-        }
-    }
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
 
-    Err(ApiError::MissingRecord(Error404::Info(format!(
-        "No host found with provided public key. {:?}",
-        pub_key
-    ))))
+    let field = |name: &str| {
+        record
+            .as_ref()
+            .and_then(|record| record.get_str(name).ok())
+            .map(String::from)
+    };
+    Ok(HostRegistrationInfo {
+        status: match &record {
+            Some(_) => RegistrationStatus::Registered,
+            None => RegistrationStatus::Unregistered,
+        },
+        given_names: field("givenNames"),
+        last_name: field("lastName"),
+        email: field("email"),
+    })
 }
 
-pub fn decode_pubkey(holoport_id: &str) -> VerifyingKey {
-    let decoded_pubkey = base36::decode(holoport_id).unwrap();
-    VerifyingKey::from_bytes(&decoded_pubkey.try_into().unwrap()).unwrap()
+/// Decodes ed25519 public key of a host from its base36 encoded holoport id
+pub fn decode_pubkey(holoport_id: &str) -> Result<VerifyingKey, ApiError> {
+    let invalid_holoport_id = |details: String| {
+        ApiError::InvalidHoloportId(Error400::Reason(Rejection::new(
+            RejectReason::InvalidHoloportId,
+            format!(
+                "Holoport id {:?} is not a valid public key. {}",
+                holoport_id, details
+            ),
+        )))
+    };
+
+    let decoded_pubkey =
+        base36::decode(holoport_id).map_err(|e| invalid_holoport_id(format!("Error: {:?}", e)))?;
+    let key_bytes: [u8; PUBLIC_KEY_LENGTH] =
+        decoded_pubkey.as_slice().try_into().map_err(|_| {
+            invalid_holoport_id(format!(
+                "Decoded key is {} bytes long, expected {}.",
+                decoded_pubkey.len(),
+                PUBLIC_KEY_LENGTH
+            ))
+        })?;
+    VerifyingKey::from_bytes(&key_bytes).map_err(|e| invalid_holoport_id(format!("Error: {:?}", e)))
 }
 
-pub async fn add_host_stats(stats: HostStats, pool: &State<AppDbPool>) -> Result<(), ApiError> {
-    let ed25519_pubkey = decode_pubkey(&stats.holoport_id);
+/// Look up host in registration records and decide what to do with its report according to `policy`.
+/// Returns status to store with the report or an error if report has to be rejected
+pub async fn registration_status(
+    holoport_id: &str,
+    policy: RegistrationPolicy,
+    registrations: &RegistrationIndex,
+    db: &Client,
+) -> Result<RegistrationStatus, ApiError> {
+    if policy == RegistrationPolicy::Off {
+        return Ok(RegistrationStatus::Unchecked);
+    }
+
+    let ed25519_pubkey = decode_pubkey(holoport_id)?;
+    let pub_key = to_holochain_encoded_agent_key(&ed25519_pubkey);
+    match registrations.is_registered(&pub_key, db).await? {
+        true => Ok(RegistrationStatus::Registered),
+        false if policy == RegistrationPolicy::Tag => Ok(RegistrationStatus::Unregistered),
+        false => Err(ApiError::MissingRecord(Error404::Info(format!(
+            "Provided host's holoport_id is not registered among valid hosts. Public key: {:?}",
+            pub_key
+        )))),
+    }
+}
 
+/// Stores a single report, through `queue` if write-behind is enabled.
+/// With `dedup` reports that do not change state of the host only refresh the previous document
+pub async fn add_host_stats(
+    stats: HostStats,
+    policy: RegistrationPolicy,
+    registrations: &RegistrationIndex,
+    queue: Option<&IngestQueue>,
+    dedup: Option<&ReportDedup>,
+    pool: &State<AppDbPool>,
+) -> Result<(), ApiError> {
     // Confirm host exists in registration records
-    let _ = verify_host(to_holochain_encoded_agent_key(&ed25519_pubkey), &pool.mongo)
-        .await
-        .map_err(|e| {
-            ApiError::MissingRecord(Error404::Info(format!(
-                "Provided host's holoport_id is not registered among valid hosts.  Error: {:?}",
-                e
-            )))
-        });
+    let status =
+        registration_status(&stats.holoport_id, policy, registrations, &pool.mongo).await?;
 
-    // Add utc timestamp to stats payload and insert into db
+    // Timestamp signed by host was already checked against server time, so it is stored as is
     let holoport_status = HostStats {
-        timestamp: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .ok()
-            .map(|t| i64::try_from(t.as_secs()).ok().unwrap_or(0)),
+        registration_status: Some(status),
         ..stats
     };
-    add_holoport_status(holoport_status, &pool.mongo).await?;
-    Ok(())
+
+    if let Some(dedup) = dedup {
+        if let Some(id) = dedup.unchanged(&holoport_status) {
+            // Document may be gone (cleaned up) or not yet written (queued), then store it again
+            if refresh_holoport_status(id, &holoport_status, &pool.mongo).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    let val = holoport_status_doc(holoport_status.clone())?;
+    if let (Some(dedup), Ok(id)) = (dedup, val.get_object_id("_id")) {
+        dedup.stored(&holoport_status, id);
+    }
+
+    match queue {
+        Some(queue) => queue.enqueue(val),
+        None => add_holoport_status(val, &pool.mongo).await,
+    }
+}
+
+// Store all verified reports of a batch with a single insert and report outcome of each one
+pub async fn add_host_stats_batch(
+    batch: HostStatsBatch,
+    policy: RegistrationPolicy,
+    registrations: &RegistrationIndex,
+    dedup: Option<&ReportDedup>,
+    pool: &State<AppDbPool>,
+) -> Result<Vec<BatchReportResult>, ApiError> {
+    let mut results = Vec::with_capacity(batch.reports.len());
+    let mut reports = vec![];
+
+    for (index, report) in batch.reports.into_iter().enumerate() {
+        let outcome = match report {
+            Ok(stats) => {
+                match registration_status(&stats.holoport_id, policy, registrations, &pool.mongo)
+                    .await
+                {
+                    Ok(status) => {
+                        let holoport_status = HostStats {
+                            registration_status: Some(status),
+                            ..stats
+                        };
+                        holoport_status_doc(holoport_status.clone())
+                            .map(|doc| (holoport_status, doc))
+                    }
+                    // Failure of database fails whole batch so that host retries it later
+                    Err(e @ ApiError::Database(_)) => return Err(e),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        results.push(BatchReportResult::new(
+            index,
+            outcome.as_ref().map(|(holoport_status, _)| holoport_status),
+        ));
+        if let Ok(report) = outcome {
+            reports.push(report);
+        }
+    }
+
+    let docs = match dedup {
+        Some(dedup) => dedup_batch(dedup, reports, &pool.mongo).await?,
+        None => reports.into_iter().map(|(_, doc)| doc).collect(),
+    };
+    add_holoport_statuses(&docs, &pool.mongo).await?;
+    Ok(results)
+}
+
+// Returns documents of batch `reports` that change state of their hosts, after refreshing
+// documents of hosts whose state did not change
+async fn dedup_batch(
+    dedup: &ReportDedup,
+    reports: Vec<(HostStats, Document)>,
+    db: &Client,
+) -> Result<Vec<Document>, ApiError> {
+    let ids = reports
+        .iter()
+        .map(|(stats, doc)| {
+            doc.get_object_id("_id")
+                .map(|id| (stats, id))
+                .map_err(|e| ApiError::Database(Debug(mongodb::error::Error::custom(e))))
+        })
+        .collect::<Result<Vec<(&HostStats, ObjectId)>, ApiError>>()?;
+    let plan = dedup.batch(&ids);
+
+    let (stats, mut docs): (Vec<HostStats>, Vec<Option<Document>>) = reports
+        .into_iter()
+        .map(|(stats, doc)| (stats, Some(doc)))
+        .unzip();
+    let mut stored = vec![];
+    for (id, last) in plan.refreshed {
+        // Document may be gone (cleaned up) or not yet written (queued), then store the latest report
+        if !refresh_holoport_status(id, &stats[last], db).await? {
+            stored.extend(docs[last].take());
+        }
+    }
+    for (first, last) in plan.inserted {
+        if let Some(mut doc) = docs[first].take() {
+            if let Some(last) = last {
+                doc.insert("lastSeen", stats[last].timestamp);
+                for (key, value) in gauge_fields(&stats[last]) {
+                    doc.insert(key, value);
+                }
+            }
+            stored.push(doc);
+        }
+    }
+    Ok(stored)
 }
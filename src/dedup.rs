@@ -0,0 +1,195 @@
+use mongodb::bson::oid::ObjectId;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use crate::types::HostStats;
+
+// Remembers the state last stored for each host, so that reports identical to the previous one
+// only refresh `lastSeen` of the stored document instead of adding a new one.
+// A report that comes more than `max_gap` seconds after the last one starts a new document even
+// if it is identical, so that gaps in reporting stay visible to uptime computation.
+// Managed by Rocket as a State, hence Mutex
+pub struct ReportDedup {
+    max_gap: i64,
+    stored: Mutex<HashMap<String, StoredState>>,
+}
+
+#[derive(Clone, Copy)]
+struct StoredState {
+    fingerprint: u64,
+    // _id of the document in `holoport_status` holding the state
+    id: ObjectId,
+    // Timestamp of the last report with the state
+    last_seen: i64,
+}
+
+// How reports of a batch are stored, reports are referred to by their position in the batch
+#[derive(Default, Debug, PartialEq)]
+pub struct BatchPlan {
+    // Reports stored as new documents, each with the last of identical reports that follow it
+    pub inserted: Vec<(usize, Option<usize>)>,
+    // Documents stored earlier, each with the last of identical reports that follow it
+    pub refreshed: Vec<(ObjectId, usize)>,
+}
+
+// Document that following identical reports of a host are merged into
+#[derive(Clone, Copy)]
+enum Target {
+    Stored(ObjectId),
+    // Position in `BatchPlan::inserted`
+    Inserted(usize),
+}
+
+impl ReportDedup {
+    pub fn new(max_gap: u64) -> Self {
+        ReportDedup {
+            max_gap: max_gap as i64,
+            stored: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `_id` of the stored document if `stats` does not change state of the host
+    pub fn unchanged(&self, stats: &HostStats) -> Option<ObjectId> {
+        let fingerprint = fingerprint(stats);
+        let mut stored = self.stored.lock().expect("dedup lock poisoned");
+        let timestamp = stats.timestamp.unwrap_or(0);
+        match stored.get_mut(&stats.holoport_id) {
+            Some(state) if self.continues(state, fingerprint, timestamp) => {
+                state.last_seen = state.last_seen.max(timestamp);
+                Some(state.id)
+            }
+            _ => None,
+        }
+    }
+
+    // Report continues `state` only if it is identical and host did not stop reporting in between,
+    // so that the document never covers time when host was silent
+    fn continues(&self, state: &StoredState, fingerprint: u64, timestamp: i64) -> bool {
+        state.fingerprint == fingerprint && timestamp - state.last_seen <= self.max_gap
+    }
+
+    /// Records that state of `stats` was stored in document `id`
+    pub fn stored(&self, stats: &HostStats, id: ObjectId) {
+        self.stored.lock().expect("dedup lock poisoned").insert(
+            stats.holoport_id.clone(),
+            StoredState {
+                fingerprint: fingerprint(stats),
+                id,
+                last_seen: stats.timestamp.unwrap_or(0),
+            },
+        );
+    }
+
+    /// Decides which `reports` of a batch, paired with `_id` of their documents, are stored and which
+    /// only refresh a document. Reports of each host are processed in order of their timestamps,
+    /// the first one continues the stored state of the host only if it is not older than that state.
+    /// Remembers the latest state of each host, unless a newer one is remembered already
+    pub fn batch(&self, reports: &[(&HostStats, ObjectId)]) -> BatchPlan {
+        let mut by_host: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (position, (stats, _)) in reports.iter().enumerate() {
+            by_host
+                .entry(stats.holoport_id.as_str())
+                .or_default()
+                .push(position);
+        }
+
+        let mut stored = self.stored.lock().expect("dedup lock poisoned");
+        let mut plan = BatchPlan::default();
+        let mut refreshed: HashMap<ObjectId, usize> = HashMap::new();
+        for (holoport_id, mut positions) in by_host {
+            positions.sort_by_key(|&position| (reports[position].0.timestamp, position));
+            let timestamp = |position: usize| reports[position].0.timestamp.unwrap_or(0);
+
+            let cached = stored.get(holoport_id).copied();
+            let first = timestamp(positions[0]);
+            let mut current = cached
+                .filter(|state| first >= state.last_seen)
+                .map(|state| (state, Target::Stored(state.id)));
+            for position in positions {
+                let fingerprint = fingerprint(reports[position].0);
+                let timestamp = timestamp(position);
+                let (target, id) = match current {
+                    Some((state, Target::Stored(id)))
+                        if self.continues(&state, fingerprint, timestamp) =>
+                    {
+                        refreshed.insert(id, position);
+                        (Target::Stored(id), id)
+                    }
+                    Some((state, Target::Inserted(inserted)))
+                        if self.continues(&state, fingerprint, timestamp) =>
+                    {
+                        plan.inserted[inserted].1 = Some(position);
+                        (Target::Inserted(inserted), state.id)
+                    }
+                    _ => {
+                        plan.inserted.push((position, None));
+                        (
+                            Target::Inserted(plan.inserted.len() - 1),
+                            reports[position].1,
+                        )
+                    }
+                };
+                let state = StoredState {
+                    fingerprint,
+                    id,
+                    last_seen: timestamp,
+                };
+                current = Some((state, target));
+            }
+
+            match (current, cached) {
+                (Some((latest, _)), Some(state)) if latest.last_seen < state.last_seen => {}
+                (Some((latest, _)), _) => {
+                    stored.insert(holoport_id.to_string(), latest);
+                }
+                (None, _) => {}
+            }
+        }
+
+        plan.inserted.sort_unstable();
+        plan.refreshed = refreshed.into_iter().collect();
+        plan.refreshed
+            .sort_unstable_by_key(|(_, position)| *position);
+        plan
+    }
+}
+
+/// Hash of all fields describing state of a host. Fields that are expected to change with every
+/// report (`timestamp`, `nonce` and gauges like `cpuLoad`) are left out
+fn fingerprint(stats: &HostStats) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    stats.holo_network.hash(&mut hasher);
+    stats.channel.hash(&mut hasher);
+    stats.holoport_model.hash(&mut hasher);
+    stats.ssh_status.hash(&mut hasher);
+    stats.zt_ip.hash(&mut hasher);
+    stats.wan_ip.hash(&mut hasher);
+    stats.channel_version.hash(&mut hasher);
+    stats.hpos_version.hash(&mut hasher);
+    stats.registration_status.hash(&mut hasher);
+    stats.schema_version.hash(&mut hasher);
+    stats.memory_total.hash(&mut hasher);
+    stats.disk_total.hash(&mut hasher);
+    stats.kernel_version.hash(&mut hasher);
+    stats.holochain_version.hash(&mut hasher);
+
+    // AppStatusFilter implements neither Hash nor Ord, so apps are hashed
+    // in order of their ids with statuses in serialized form
+    match &stats.hpos_app_list {
+        None => 0.hash(&mut hasher),
+        Some(apps) => {
+            let mut apps: Vec<_> = apps.iter().collect();
+            apps.sort_by(|a, b| a.0.cmp(b.0));
+            for (app_id, status) in apps {
+                app_id.hash(&mut hasher);
+                serde_json::to_string(status)
+                    .unwrap_or_default()
+                    .hash(&mut hasher);
+            }
+        }
+    }
+
+    hasher.finish()
+}
@@ -1,4 +1,18 @@
-use crate::types::{ApiError, HostInfo, HostStats, Result, ZerotierMember};
+use holochain_conductor_api::AppStatusFilter;
+use holochain_types::app::InstalledAppId;
+use hpos_config_core::public_key::to_holochain_encoded_agent_key;
+use rocket::futures::stream::{self, StreamExt};
+use rocket::serde::json::Json;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+use crate::db::decode_pubkey;
+use crate::types::{
+    ApiError, Error400, ExportFormat, HappHost, HappInventory, HostDetail, HostFilter, HostInfo,
+    HostInfoExport, HostRegistrationInfo, HostSearch, HostStats, HostStatsRecord, Page,
+    PendingUpgrade, PendingUpgrades, Result, SilentHost, SilentHosts, VersionCount, VersionKey,
+    VersionRollout, ZerotierMember,
+};
 
 /// Returns all available hosts listed in `host_statistics.holoport_status`
 /// and `host_statistics.latest_raw_snap` collections. Function merges two data sets
@@ -6,11 +20,352 @@ use crate::types::{ApiError, HostInfo, HostStats, Result, ZerotierMember};
 /// reports to user in `errors` field
 /// Hosts losted in `host_statistics.holoport_status` but not listed in `host_statistics.latest_raw_snap`
 /// are also marked as flawed
+/// Hosts are sorted by `host_sort_key`, so that order is the same between calls
 pub async fn list_available_hosts(
     hosts: Vec<HostStats>,
     members: Vec<ZerotierMember>,
 ) -> Result<Vec<HostInfo>, ApiError> {
-    Ok(merge_host_info(hosts, members))
+    let mut host_info_vec = merge_host_info(hosts, members);
+    host_info_vec.sort_by_cached_key(host_sort_key);
+    Ok(host_info_vec)
+}
+
+/// Returns hosts as listed by `list_available_hosts` that match all the set parameters of `search`.
+/// Hosts are matched with registrations by their holoport id, `email_keys` and `q_keys` are
+/// agent pub keys of registrations made with email `search.email` and `search.q` respectively
+pub fn search_hosts(
+    hosts: Vec<HostStats>,
+    members: Vec<ZerotierMember>,
+    search: &HostSearch,
+    email_keys: &HashSet<String>,
+    q_keys: &HashSet<String>,
+) -> Vec<HostInfo> {
+    // Merged HostInfo holds WAN IP of ZeroTier member, while host can report its own
+    let reported_wan_ips: HashMap<String, Option<String>> = hosts
+        .iter()
+        .map(|host| (host.holoport_id.clone(), host.wan_ip.clone()))
+        .collect();
+
+    let mut found = merge_host_info(hosts, members);
+    found.retain(|host| {
+        let reported_wan_ip = host
+            .holoport_id
+            .as_ref()
+            .and_then(|id| reported_wan_ips.get(id).cloned())
+            .flatten();
+        let matches_zt_ip = |value: &str| host.zerotier_ip.as_deref() == Some(value);
+        let matches_wan_ip = |value: &str| {
+            [host.wan_ip.as_deref(), reported_wan_ip.as_deref()]
+                .iter()
+                .flatten()
+                .any(|address| is_address_of(address, value))
+        };
+        let matches_email = |value: &str, keys: &HashSet<String>| {
+            host.registered_email
+                .as_deref()
+                .is_some_and(|email| email.eq_ignore_ascii_case(value))
+                || (!keys.is_empty()
+                    && host
+                        .holoport_id
+                        .as_deref()
+                        .and_then(agent_pub_key)
+                        .is_some_and(|key| keys.contains(&key)))
+        };
+        let matches_id = |value: &str| {
+            host.holoport_id
+                .as_deref()
+                .is_some_and(|id| id.eq_ignore_ascii_case(value))
+        };
+
+        unset_or(search.zt_ip.as_deref(), matches_zt_ip)
+            && unset_or(search.wan_ip.as_deref(), matches_wan_ip)
+            && unset_or(search.email.as_deref(), |email| {
+                matches_email(email, email_keys)
+            })
+            && unset_or(search.q.as_deref(), |q| {
+                matches_zt_ip(q) || matches_wan_ip(q) || matches_email(q, q_keys) || matches_id(q)
+            })
+    });
+    found.sort_by_cached_key(host_sort_key);
+    found
+}
+
+// ZeroTier lists physical address of a member together with a port, e.g. `1.2.3.4/9993`
+fn is_address_of(address: &str, ip: &str) -> bool {
+    address == ip
+        || address
+            .strip_prefix(ip)
+            .is_some_and(|port| port.starts_with('/'))
+}
+
+// Holochain encoded agent pub key of a host, as stored in registrations
+fn agent_pub_key(holoport_id: &str) -> Option<String> {
+    decode_pubkey(holoport_id)
+        .ok()
+        .map(|key| to_holochain_encoded_agent_key(&key))
+}
+
+type HostSortKey = (Option<String>, Option<i64>, Option<String>, Option<String>);
+
+// Sort key of the last host on a page, with number of hosts that share it up to and including that host,
+// as ZeroTier members without IP and reports do not differ in the key
+type HostCursor = (HostSortKey, usize);
+
+fn host_sort_key(host: &HostInfo) -> HostSortKey {
+    (
+        host.holoport_id.clone(),
+        host.last_netstatsd_reported,
+        host.zerotier_ip.clone(),
+        host.wan_ip.clone(),
+    )
+}
+
+/// Returns at most `limit` of sorted `hosts` that come after the host identified by `cursor`.
+/// Cursor is an url-safe encoding of the sort key of the last host on the previous page and its position
+/// among hosts with the same key
+pub fn paginate_hosts(
+    hosts: Vec<HostInfo>,
+    limit: Option<usize>,
+    cursor: Option<&str>,
+) -> Result<Page<Vec<HostInfo>>, ApiError> {
+    let total = hosts.len();
+    let start = match cursor {
+        Some(cursor) => {
+            let (last, seen) = decode_host_cursor(cursor)?;
+            let first = hosts.partition_point(|host| host_sort_key(host) < last);
+            let after = hosts.partition_point(|host| host_sort_key(host) <= last);
+            first.saturating_add(seen).min(after)
+        }
+        None => 0,
+    };
+    let end = limit.map_or(total, |limit| start.saturating_add(limit.max(1)).min(total));
+
+    // Page holds at least one host, unless cursor points past the last one
+    let next_cursor = (end < total).then(|| {
+        let last = host_sort_key(&hosts[end - 1]);
+        let first = hosts.partition_point(|host| host_sort_key(host) < last);
+        encode_host_cursor(&(last, end - first))
+    });
+    let page = hosts.into_iter().skip(start).take(end - start).collect();
+
+    Ok(Page {
+        inner: page,
+        total,
+        next_cursor,
+    })
+}
+
+fn encode_host_cursor(cursor: &HostCursor) -> String {
+    base64::encode_config(
+        serde_json::to_vec(cursor).unwrap_or_default(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn decode_host_cursor(cursor: &str) -> Result<HostCursor, ApiError> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(ApiError::BadRequest(Error400::Message(
+            "Cursor is invalid, use `X-Next-Cursor` header returned with the previous page",
+        )))
+}
+
+/// Keeps only hosts matching all the fields set in `filter`. Hosts known only
+/// to zerotier have no reported fields, so they match only `has_errors`
+pub fn filter_hosts(hosts: Vec<HostInfo>, filter: &HostFilter) -> Vec<HostInfo> {
+    hosts
+        .into_iter()
+        .filter(|host| {
+            matches_value(&host.holo_network, &filter.holo_network)
+                && matches_value(&host.channel, &filter.channel)
+                && matches_value(&host.holoport_model, &filter.holoport_model)
+                && matches_value(&host.ssh_status, &filter.ssh_status)
+                && matches_value(&host.hpos_version, &filter.hpos_version)
+                && matches_value(&Some(!host.errors.is_empty()), &filter.has_errors)
+                && unset_or(filter.running_app.as_ref(), |app_id| {
+                    host.hpos_app_list
+                        .as_ref()
+                        .and_then(|apps| apps.get(app_id))
+                        .is_some_and(|status| matches!(status, AppStatusFilter::Running))
+                })
+        })
+        .collect()
+}
+
+fn matches_value<T: PartialEq>(value: &Option<T>, expected: &Option<T>) -> bool {
+    expected.is_none() || value == expected
+}
+
+// Unset filter matches every host
+fn unset_or<T>(expected: Option<T>, matches: impl FnOnce(T) -> bool) -> bool {
+    match expected {
+        Some(expected) => matches(expected),
+        None => true,
+    }
+}
+
+/// Converts sorted `hosts` to response of requested `format`
+pub fn export_hosts(hosts: Vec<HostInfo>, format: ExportFormat) -> HostInfoExport {
+    match format {
+        ExportFormat::Json => HostInfoExport::Json(Json(hosts)),
+        ExportFormat::Csv => HostInfoExport::Csv(
+            stream::iter(iter::once(csv_header()).chain(hosts.into_iter().map(csv_row))).boxed(),
+        ),
+        ExportFormat::Ndjson => HostInfoExport::Ndjson(
+            stream::iter(
+                hosts
+                    .into_iter()
+                    .filter_map(|host| serde_json::to_string(&host).ok().map(|line| line + "\n")),
+            )
+            .boxed(),
+        ),
+    }
+}
+
+const CSV_COLUMNS: [&str; 29] = [
+    "zerotierIp",
+    "wanIp",
+    "lastZerotierOnline",
+    "lastNetstatsdReported",
+    "holoportId",
+    "registeredEmail",
+    "holoNetwork",
+    "channel",
+    "holoportModel",
+    "sshStatus",
+    "channelVersion",
+    "hposVersion",
+    "registrationStatus",
+    "schemaVersion",
+    "cpuLoad",
+    "memoryTotal",
+    "memoryUsed",
+    "diskTotal",
+    "diskUsed",
+    "uptimeSeconds",
+    "kernelVersion",
+    "holochainVersion",
+    "pendingReboot",
+    "pendingSince",
+    "appsEnabled",
+    "appsDisabled",
+    "appsRunning",
+    "appsStopped",
+    "appsPaused",
+];
+
+fn csv_header() -> String {
+    let mut header = CSV_COLUMNS.join(",");
+    header.push_str(",errors\n");
+    header
+}
+
+/// Single line of csv export. `hpos_app_list` is flattened into number of apps in each status
+/// and `errors` are joined into a single field
+fn csv_row(host: HostInfo) -> String {
+    // enabled, disabled, running, stopped, paused
+    let mut app_counts = [0usize; 5];
+    for status in host.hpos_app_list.iter().flat_map(|apps| apps.values()) {
+        let i = match status {
+            AppStatusFilter::Enabled => 0,
+            AppStatusFilter::Disabled => 1,
+            AppStatusFilter::Running => 2,
+            AppStatusFilter::Stopped => 3,
+            AppStatusFilter::Paused => 4,
+        };
+        app_counts[i] += 1;
+    }
+    let registration_status = host
+        .registration_status
+        .and_then(|status| serde_json::to_value(status).ok())
+        .and_then(|status| status.as_str().map(String::from));
+
+    let mut fields = vec![
+        csv_field(host.zerotier_ip),
+        csv_field(host.wan_ip),
+        csv_field(host.last_zerotier_online),
+        csv_field(host.last_netstatsd_reported),
+        csv_field(host.holoport_id),
+        csv_field(host.registered_email),
+        csv_field(host.holo_network),
+        csv_field(host.channel),
+        csv_field(host.holoport_model),
+        csv_field(host.ssh_status),
+        csv_field(host.channel_version),
+        csv_field(host.hpos_version),
+        csv_field(registration_status),
+        csv_field(host.schema_version),
+        csv_field(host.cpu_load),
+        csv_field(host.memory_total),
+        csv_field(host.memory_used),
+        csv_field(host.disk_total),
+        csv_field(host.disk_used),
+        csv_field(host.uptime_seconds),
+        csv_field(host.kernel_version),
+        csv_field(host.holochain_version),
+        csv_field(host.pending_reboot),
+        csv_field(host.pending_since),
+    ];
+    fields.extend(app_counts.iter().map(|count| count.to_string()));
+    fields.push(csv_field(Some(host.errors.join("; "))));
+
+    let mut row = fields.join(",");
+    row.push('\n');
+    row
+}
+
+/// Formats value as a csv field, quoting it when needed. Missing value is an empty field
+fn csv_field<T: ToString>(value: Option<T>) -> String {
+    let value = value.map(|v| v.to_string()).unwrap_or_default();
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Counts `hosts` by versions of HPOS and channel they reported last
+pub fn version_rollout(
+    hosts: &[HostStats],
+    mut first_seen: HashMap<VersionKey, i64>,
+) -> VersionRollout {
+    let mut counts: HashMap<VersionKey, u64> = HashMap::new();
+    for host in hosts {
+        let key = (
+            host.hpos_version.clone(),
+            host.channel_version.clone(),
+            host.channel.clone(),
+        );
+        *counts.entry(key).or_default() += 1;
+    }
+
+    let total_hosts = hosts.len() as u64;
+    let mut versions: Vec<VersionCount> = counts
+        .into_iter()
+        .map(|(key, count)| VersionCount {
+            first_seen: first_seen.remove(&key),
+            hpos_version: key.0,
+            channel_version: key.1,
+            channel: key.2,
+            hosts: count,
+            percentage: 100.0 * count as f64 / total_hosts as f64,
+        })
+        .collect();
+    versions.sort_by(|a, b| {
+        b.hosts.cmp(&a.hosts).then_with(|| {
+            (&a.hpos_version, &a.channel_version, &a.channel).cmp(&(
+                &b.hpos_version,
+                &b.channel_version,
+                &b.channel,
+            ))
+        })
+    });
+
+    VersionRollout {
+        total_hosts,
+        versions,
+    }
 }
 
 /// Takes each `member` and finds corresponding `host` by matching on `zerotier_ip`.
@@ -25,6 +380,7 @@ fn merge_host_info(mut hosts: Vec<HostStats>, mut members: Vec<ZerotierMember>)
         let mut errors: Vec<String> = vec![];
         let host = find_in_hosts(&mut hosts, &member.zerotier_ip, &mut errors);
         let holoport_id = resolve_holoport_id(host.holoport_id, member.name, &mut errors);
+        let pending_reboot = pending_reboot(&host.hpos_version, &host.channel_version);
 
         host_info_vec.push(HostInfo {
             zerotier_ip: member.zerotier_ip,
@@ -40,6 +396,18 @@ fn merge_host_info(mut hosts: Vec<HostStats>, mut members: Vec<ZerotierMember>)
             hpos_app_list: host.hpos_app_list,
             channel_version: host.channel_version,
             hpos_version: host.hpos_version,
+            registration_status: host.registration_status,
+            schema_version: host.schema_version,
+            cpu_load: host.cpu_load,
+            memory_total: host.memory_total,
+            memory_used: host.memory_used,
+            disk_total: host.disk_total,
+            disk_used: host.disk_used,
+            uptime_seconds: host.uptime_seconds,
+            kernel_version: host.kernel_version,
+            pending_reboot,
+            pending_since: None,
+            holochain_version: host.holochain_version,
             errors,
         });
     }
@@ -49,6 +417,7 @@ fn merge_host_info(mut hosts: Vec<HostStats>, mut members: Vec<ZerotierMember>)
     // or that this zt_ip was not present in members at all
     // In both cases we need to set an error
     while let Some(host) = hosts.pop() {
+        let pending_reboot = pending_reboot(&host.hpos_version, &host.channel_version);
         host_info_vec.push(HostInfo {
             zerotier_ip: host.zt_ip.clone(),
             wan_ip: None,
@@ -63,6 +432,18 @@ fn merge_host_info(mut hosts: Vec<HostStats>, mut members: Vec<ZerotierMember>)
             hpos_app_list: host.hpos_app_list,
             channel_version: host.channel_version,
             hpos_version: host.hpos_version,
+            registration_status: host.registration_status,
+            schema_version: host.schema_version,
+            cpu_load: host.cpu_load,
+            memory_total: host.memory_total,
+            memory_used: host.memory_used,
+            disk_total: host.disk_total,
+            disk_used: host.disk_used,
+            uptime_seconds: host.uptime_seconds,
+            kernel_version: host.kernel_version,
+            pending_reboot,
+            pending_since: None,
+            holochain_version: host.holochain_version,
             errors: vec![format!(
                 "Netstatsd reported zerotier IP as {} but Zerotier Central has no knowledge of it",
                 host.zt_ip.unwrap_or("None".into())
@@ -73,6 +454,199 @@ fn merge_host_info(mut hosts: Vec<HostStats>, mut members: Vec<ZerotierMember>)
     host_info_vec
 }
 
+/// Puts together everything known about host `holoport_id`. `members` are ZeroTier members
+/// with the same IP as in `report` or named after the host, the one matching IP is preferred.
+/// Email the host was registered with is removed from ZeroTier member unless `include_pii` is set.
+/// Returns None if neither `holoport_status`, ZeroTier nor `performance_summary` know the host
+pub fn host_detail(
+    holoport_id: String,
+    report: Option<HostStatsRecord>,
+    members: Vec<ZerotierMember>,
+    uptime: Option<f32>,
+    registration: HostRegistrationInfo,
+    include_pii: bool,
+) -> Option<HostDetail> {
+    if report.is_none() && members.is_empty() && uptime.is_none() {
+        return None;
+    }
+
+    let zerotier_ip = report
+        .as_ref()
+        .and_then(|report| report.stats.zt_ip.clone());
+    let zerotier = members
+        .iter()
+        .find(|member| zerotier_ip.is_some() && member.zerotier_ip == zerotier_ip)
+        .or_else(|| {
+            members
+                .iter()
+                .find(|member| member.name.as_ref() == Some(&holoport_id))
+        })
+        .cloned()
+        .map(|mut member| {
+            if !include_pii {
+                member.description = None;
+            }
+            member
+        });
+
+    let hosts = report.iter().map(|report| report.stats.clone()).collect();
+    let errors = merge_host_info(hosts, members)
+        .into_iter()
+        .flat_map(|host| host.errors)
+        .collect();
+
+    Some(HostDetail {
+        holoport_id,
+        report,
+        zerotier,
+        uptime,
+        registration,
+        errors,
+    })
+}
+
+/// Counts hosts by status of each app installed on them, according to their latest report.
+/// Apps hosted by the most hosts go first
+pub fn happ_inventory(hosts: &[HostStats]) -> Vec<HappInventory> {
+    let mut inventory: HashMap<&InstalledAppId, HappInventory> = HashMap::new();
+    for (app_id, status) in hosts
+        .iter()
+        .filter_map(|host| host.hpos_app_list.as_ref())
+        .flatten()
+    {
+        inventory
+            .entry(app_id)
+            .or_insert_with(|| HappInventory {
+                app_id: app_id.clone(),
+                ..Default::default()
+            })
+            .add_host(status);
+    }
+
+    let mut inventory: Vec<HappInventory> = inventory.into_values().collect();
+    inventory.sort_by(|a, b| b.hosts.cmp(&a.hosts).then_with(|| a.app_id.cmp(&b.app_id)));
+    inventory
+}
+
+/// Lists hosts that have `app_id` installed, with its status in their latest report
+pub fn happ_hosts(hosts: Vec<HostStats>, app_id: &str) -> Vec<HappHost> {
+    let mut happ_hosts: Vec<HappHost> = hosts
+        .into_iter()
+        .filter_map(|mut host| {
+            let status = host.hpos_app_list.as_mut()?.remove(app_id)?;
+            Some(HappHost {
+                holoport_id: host.holoport_id,
+                status,
+                last_netstatsd_reported: host.timestamp,
+            })
+        })
+        .collect();
+    happ_hosts.sort_by(|a, b| a.holoport_id.cmp(&b.holoport_id));
+    happ_hosts
+}
+
+/// Host pending reboot has downloaded revision of HPOS different than the one it is running
+pub fn pending_reboot(
+    hpos_version: &Option<String>,
+    channel_version: &Option<String>,
+) -> Option<bool> {
+    match (hpos_version, channel_version) {
+        (Some(hpos_version), Some(channel_version)) => Some(hpos_version != channel_version),
+        _ => None,
+    }
+}
+
+/// Sets `pending_since` of hosts pending reboot out of `pending_since` map keyed by holoport id
+pub fn set_pending_since(hosts: &mut [HostInfo], pending_since: &HashMap<String, i64>) {
+    for host in hosts
+        .iter_mut()
+        .filter(|host| host.pending_reboot == Some(true))
+    {
+        host.pending_since = host
+            .holoport_id
+            .as_ref()
+            .and_then(|id| pending_since.get(id))
+            .copied();
+    }
+}
+
+/// Lists `hosts` pending reboot, the longest pending first
+pub fn pending_upgrades(
+    hosts: Vec<HostStats>,
+    pending_since: &HashMap<String, i64>,
+) -> PendingUpgrades {
+    let total_hosts = hosts.len() as u64;
+    let mut pending: Vec<PendingUpgrade> = hosts
+        .into_iter()
+        .filter(|host| pending_reboot(&host.hpos_version, &host.channel_version) == Some(true))
+        .map(|host| PendingUpgrade {
+            pending_since: pending_since.get(&host.holoport_id).copied(),
+            holoport_id: host.holoport_id,
+            channel: host.channel,
+            hpos_version: host.hpos_version,
+            channel_version: host.channel_version,
+        })
+        .collect();
+    // Hosts with unknown pending_since go last
+    pending.sort_by_key(|host| {
+        (
+            host.pending_since.unwrap_or(i64::MAX),
+            host.holoport_id.clone(),
+        )
+    });
+
+    PendingUpgrades {
+        total_hosts,
+        pending_hosts: pending.len() as u64,
+        hosts: pending,
+    }
+}
+
+/// Lists `hosts` that have not reported since `cutoff` (unix seconds) together with the time
+/// ZeroTier has last seen them. ZeroTier member of a host is matched by its reported IP or by name
+pub fn silent_hosts(hosts: Vec<HostStats>, members: &[ZerotierMember], cutoff: i64) -> SilentHosts {
+    let total_hosts = hosts.len() as u64;
+    let find_member = |host: &HostStats| {
+        members
+            .iter()
+            .find(|member| host.zt_ip.is_some() && member.zerotier_ip == host.zt_ip)
+            .or_else(|| {
+                members
+                    .iter()
+                    .find(|member| member.name.as_ref() == Some(&host.holoport_id))
+            })
+    };
+
+    let mut silent: Vec<SilentHost> = hosts
+        .into_iter()
+        // Host that never reported its timestamp counts as silent
+        .filter(|host| unset_or(host.timestamp, |timestamp| timestamp < cutoff))
+        .map(|host| {
+            let member = find_member(&host);
+            SilentHost {
+                last_zerotier_online: member.and_then(|member| zero_to_none(member.last_online)),
+                zerotier_ip: host
+                    .zt_ip
+                    .or_else(|| member.and_then(|member| member.zerotier_ip.clone())),
+                holoport_id: host.holoport_id,
+                last_netstatsd_reported: host.timestamp,
+                holo_network: host.holo_network,
+                channel: host.channel,
+                hpos_version: host.hpos_version,
+                channel_version: host.channel_version,
+                holochain_version: host.holochain_version,
+            }
+        })
+        .collect();
+    silent.sort_by_key(|host| (host.last_netstatsd_reported, host.holoport_id.clone()));
+
+    SilentHosts {
+        total_hosts,
+        silent_hosts: silent.len() as u64,
+        hosts: silent,
+    }
+}
+
 fn zero_to_none(num: i64) -> Option<i64> {
     if num == 0 {
         return None;
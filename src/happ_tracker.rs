@@ -0,0 +1,131 @@
+use holochain_conductor_api::AppStatusFilter;
+use holochain_types::app::InstalledAppId;
+use mongodb::Client;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::db;
+use crate::types::{ApiError, HappEvent, HostStats};
+
+// Status of apps in the last report of each host, used to derive `HappEvent`s out of
+// consecutive reports. Managed by Rocket as a State, hence Mutex
+#[derive(Default)]
+pub struct HappTracker {
+    hosts: Mutex<HashMap<String, TrackedApps>>,
+}
+
+struct TrackedApps {
+    timestamp: i64,
+    apps: HashMap<InstalledAppId, AppStatusFilter>,
+}
+
+impl HappTracker {
+    /// Stores transitions of apps found in accepted `reports`. Failure is only logged,
+    /// because reports themselves are already stored
+    pub async fn record(&self, mut reports: Vec<HostStats>, db: &Client) {
+        // Reports of a batch are compared with each other in order they were made
+        reports.sort_by_key(|stats| stats.timestamp);
+
+        let mut events = vec![];
+        for stats in &reports {
+            match self.track(stats, db).await {
+                Ok(mut transitions) => events.append(&mut transitions),
+                Err(e) => rocket::warn!("Failed to track hApp status: {}", e.message()),
+            }
+        }
+        if let Err(e) = db::add_happ_events(events, db).await {
+            rocket::warn!("Failed to store hApp status transitions: {}", e.message());
+        }
+    }
+
+    /// Returns transitions of apps between the previous report of the host and `stats`, which
+    /// then becomes the previous report. Reports older than the previous one are ignored.
+    /// On the first report of a host since start the previous one is loaded from `holoport_status`
+    pub async fn track(&self, stats: &HostStats, db: &Client) -> Result<Vec<HappEvent>, ApiError> {
+        // Missing app list means netstatsd failed to collect it, not that apps were removed
+        let (timestamp, apps) = match (stats.timestamp, &stats.hpos_app_list) {
+            (Some(timestamp), Some(apps)) => (timestamp, apps),
+            _ => return Ok(vec![]),
+        };
+
+        if !self.is_tracked(&stats.holoport_id) {
+            let previous = db::get_previous_app_list(&stats.holoport_id, timestamp, db).await?;
+            if let Some((timestamp, apps)) = previous {
+                self.hosts
+                    .lock()
+                    .expect("lock poisoned")
+                    .entry(stats.holoport_id.clone())
+                    .or_insert(TrackedApps { timestamp, apps });
+            }
+        }
+
+        let mut hosts = self.hosts.lock().expect("lock poisoned");
+        match hosts.get_mut(&stats.holoport_id) {
+            Some(previous) if previous.timestamp >= timestamp => Ok(vec![]),
+            Some(previous) => {
+                let events = app_transitions(&stats.holoport_id, timestamp, &previous.apps, apps);
+                previous.timestamp = timestamp;
+                previous.apps = apps.clone();
+                Ok(events)
+            }
+            // First report of the host, there is nothing to compare with
+            None => {
+                hosts.insert(
+                    stats.holoport_id.clone(),
+                    TrackedApps {
+                        timestamp,
+                        apps: apps.clone(),
+                    },
+                );
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn is_tracked(&self, holoport_id: &str) -> bool {
+        self.hosts
+            .lock()
+            .expect("lock poisoned")
+            .contains_key(holoport_id)
+    }
+}
+
+/// Compares status of apps in two consecutive reports. Installed app has no `from` status,
+/// removed app has no `to` status
+pub fn app_transitions(
+    holoport_id: &str,
+    timestamp: i64,
+    previous: &HashMap<InstalledAppId, AppStatusFilter>,
+    current: &HashMap<InstalledAppId, AppStatusFilter>,
+) -> Vec<HappEvent> {
+    let changed = current
+        .iter()
+        .filter(|(app_id, status)| {
+            !previous
+                .get(*app_id)
+                .is_some_and(|previous| same_status(previous, status))
+        })
+        .map(|(app_id, status)| (app_id, previous.get(app_id), Some(status)));
+    let removed = previous
+        .iter()
+        .filter(|(app_id, _)| !current.contains_key(*app_id))
+        .map(|(app_id, status)| (app_id, Some(status), None));
+
+    let mut events: Vec<HappEvent> = changed
+        .chain(removed)
+        .map(|(app_id, from, to)| HappEvent {
+            holoport_id: holoport_id.to_string(),
+            app_id: app_id.clone(),
+            from: from.cloned(),
+            to: to.cloned(),
+            timestamp,
+        })
+        .collect();
+    events.sort_by(|a, b| a.app_id.cmp(&b.app_id));
+    events
+}
+
+// AppStatusFilter does not implement PartialEq, so statuses are compared in serialized form
+fn same_status(a: &AppStatusFilter, b: &AppStatusFilter) -> bool {
+    serde_json::to_string(a).ok() == serde_json::to_string(b).ok()
+}
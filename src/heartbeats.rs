@@ -0,0 +1,49 @@
+use crate::types::{ReportGap, WindowUptime};
+
+/// Computes share of the last `hours` before `now` during which host kept reporting.
+/// `reports` are (timestamp, last seen) of reports sorted by timestamp, last seen equals
+/// timestamp unless identical reports were deduplicated. Every report means that host was up
+/// until its next report is expected, `interval` seconds later with half of the interval of tolerance.
+/// Any time not covered this way is a gap in reporting, counted as downtime
+pub fn window_uptime(reports: &[(i64, i64)], hours: u64, now: i64, interval: i64) -> WindowUptime {
+    let start = now.saturating_sub(hours as i64 * 60 * 60);
+    let coverage = interval + interval / 2;
+
+    let mut gaps = vec![];
+    // End of time covered by reports processed so far
+    let mut covered_until = start;
+    let mut count = 0;
+    for &(timestamp, last_seen) in reports {
+        if timestamp > now {
+            break;
+        }
+        if last_seen.max(timestamp) + coverage < start {
+            continue;
+        }
+        if timestamp > start {
+            count += 1;
+        }
+        if timestamp > covered_until {
+            gaps.push(ReportGap {
+                from: covered_until,
+                to: timestamp,
+            });
+        }
+        covered_until = covered_until.max(last_seen.max(timestamp) + coverage);
+    }
+    if covered_until < now {
+        gaps.push(ReportGap {
+            from: covered_until,
+            to: now,
+        });
+    }
+
+    let window = (now - start).max(1);
+    let downtime: i64 = gaps.iter().map(|gap| gap.to - gap.from).sum();
+    WindowUptime {
+        hours,
+        uptime: 1.0 - downtime as f64 / window as f64,
+        reports: count,
+        gaps,
+    }
+}
@@ -0,0 +1,152 @@
+use mongodb::bson::Document;
+use mongodb::Client;
+use rocket::serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use crate::db;
+use crate::types::{ApiError, Error503, RejectReason, Rejection};
+
+// Pauses before each retry of a failed write, reports are dropped once all retries fail.
+// Queue is not read while retrying, so that hosts get 503 once it is full
+const RETRY_BACKOFF: [Duration; 3] = [
+    Duration::from_millis(500),
+    Duration::from_secs(2),
+    Duration::from_secs(8),
+];
+
+// Bounded in-process queue of `holoport_status` documents waiting to be written to database.
+// Reports are acknowledged to hosts once queued, and background task writes them with `insert_many`
+// whenever `batch_size` documents are queued or `flush_interval` passes.
+// Dropping the queue closes it, after which the task writes whatever is left and finishes
+pub struct IngestQueue {
+    sender: mpsc::Sender<Document>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    // Number of reports that were acknowledged but failed to be written
+    dropped: Arc<AtomicU64>,
+}
+
+// Return type for /hosts/ingest endpoint
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct IngestStatus {
+    // Reports waiting to be written
+    pub queued: usize,
+    // Reports dropped after all retries of their write failed, since API start
+    pub dropped: u64,
+}
+
+impl IngestQueue {
+    pub fn new(db: Client, capacity: usize, batch_size: usize, flush_interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        // Interval of 0 would panic the writer task
+        let flush_interval = flush_interval.max(Duration::from_millis(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let worker = tokio::spawn(flush_loop(
+            db,
+            receiver,
+            batch_size.max(1),
+            flush_interval,
+            dropped.clone(),
+        ));
+
+        IngestQueue {
+            sender,
+            worker: Mutex::new(Some(worker)),
+            dropped,
+        }
+    }
+
+    pub fn status(&self) -> IngestStatus {
+        IngestStatus {
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Queues document for writing, or rejects it with 503 if queue is full
+    pub fn enqueue(&self, doc: Document) -> Result<(), ApiError> {
+        self.sender.try_send(doc).map_err(|e| {
+            let message = match e {
+                TrySendError::Full(_) => "Too many reports are waiting to be stored, retry later.",
+                TrySendError::Closed(_) => "API is shutting down, retry later.",
+            };
+            ApiError::ServiceUnavailable(Error503::Reason(Rejection::new(
+                RejectReason::QueueFull,
+                message.into(),
+            )))
+        })
+    }
+
+    /// Handle of background task, which finishes once the queue is dropped and flushed.
+    /// Can be taken only once
+    pub fn take_worker(&self) -> Option<JoinHandle<()>> {
+        self.worker
+            .lock()
+            .expect("ingest queue lock poisoned")
+            .take()
+    }
+}
+
+async fn flush_loop(
+    db: Client,
+    mut receiver: mpsc::Receiver<Document>,
+    batch_size: usize,
+    flush_interval: Duration,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut buffer = Vec::with_capacity(batch_size);
+    let mut interval = tokio::time::interval(flush_interval);
+
+    loop {
+        tokio::select! {
+            doc = receiver.recv() => match doc {
+                Some(doc) => {
+                    buffer.push(doc);
+                    if buffer.len() >= batch_size {
+                        flush(&db, &mut buffer, &dropped).await;
+                    }
+                }
+                // All senders are gone, nothing more will arrive
+                None => break,
+            },
+            _ = interval.tick() => flush(&db, &mut buffer, &dropped).await,
+        }
+    }
+
+    flush(&db, &mut buffer, &dropped).await;
+}
+
+async fn flush(db: &Client, buffer: &mut Vec<Document>, dropped: &AtomicU64) {
+    if buffer.is_empty() {
+        return;
+    }
+    let docs: Vec<Document> = std::mem::take(buffer);
+    let mut backoff = RETRY_BACKOFF.iter();
+    while let Err(e) = db::add_holoport_statuses(&docs, db).await {
+        match backoff.next() {
+            Some(pause) => {
+                rocket::warn!(
+                    "Failed to store {} queued host reports, retrying in {:?}: {}",
+                    docs.len(),
+                    pause,
+                    e.message()
+                );
+                tokio::time::sleep(*pause).await;
+            }
+            None => {
+                rocket::warn!(
+                    "Dropped {} queued host reports after failing to store them: {}",
+                    docs.len(),
+                    e.message()
+                );
+                dropped.fetch_add(docs.len() as u64, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
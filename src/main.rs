@@ -1,17 +1,49 @@
+use rocket::http::Accept;
 use rocket::serde::json::Json;
 use rocket::*;
 use rocket::{self, get, post, State};
 
+mod config;
 mod db;
+mod dedup;
 mod handlers;
+mod happ_tracker;
+mod heartbeats;
+mod ingest;
+mod rate_limit;
+mod registry;
+mod replay;
+mod snapshots;
 mod types;
 
-use handlers::list_available_hosts;
-use types::{ApiError, Capacity, HostInfo, HostStats, Result, Uptime};
+use config::AppConfig;
+use dedup::ReportDedup;
+use happ_tracker::HappTracker;
+use ingest::{IngestQueue, IngestStatus};
+use rate_limit::{RateLimitRejections, RateLimiter};
+use registry::RegistrationIndex;
+use replay::{Nonce, NonceCache};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use handlers::{
+    export_hosts, filter_hosts, host_detail, list_available_hosts, paginate_hosts, pending_reboot,
+    search_hosts, set_pending_since, version_rollout,
+};
+use types::{
+    ApiError, BatchReportResult, CapacitySnapshot, Error400, ExportFormat, HappEvent, HappHost,
+    HappInventory, HostDetail, HostFilter, HostInfo, HostInfoExport, HostSearch, HostStats,
+    HostStatsBatch, HostStatsRecord, HostUptime, NetworkCapacity, Page, PendingUpgrades, Result,
+    SilentHosts, VersionRollout,
+};
 
 #[cfg(test)]
 mod test;
 
+// Cut off time in hours of fleet summaries requested without `hours`
+const DEFAULT_HOURS: u64 = 24;
+
 #[get("/")]
 async fn index(pool: &State<db::AppDbPool>) -> Result<String> {
     db::ping_database(&pool.mongo).await
@@ -22,44 +54,545 @@ async fn cleanup(pool: &State<db::AppDbPool>) -> Result<String, ApiError> {
     db::cleanup_database(&pool.mongo).await
 }
 
+// Host is identified by its holoport id, which is its name in `performance_summary`
 #[get("/<name>/uptime")]
-async fn uptime(name: String, pool: &State<db::AppDbPool>) -> Result<Option<Json<Uptime>>> {
-    if let Some(uptime) = db::host_uptime(name, &pool.mongo).await {
-        return Ok(Some(Json(uptime)));
+async fn uptime(
+    name: String,
+    pool: &State<db::AppDbPool>,
+    config: &State<AppConfig>,
+) -> Result<Option<Json<HostUptime>>, ApiError> {
+    let legacy = db::host_uptime(name.clone(), &pool.mongo).await?;
+
+    // Reports from before the longest window cover its start
+    let longest = config.uptime_windows.iter().max().copied().unwrap_or(0);
+    let interval = config.uptime_report_interval;
+    let cutoff = longest + interval.div_ceil(60 * 60) * 2;
+    let reports = db::get_report_times(&name, cutoff, &pool.mongo).await?;
+
+    if legacy.is_none() && reports.is_empty() {
+        return Ok(None);
     }
-    Ok(None)
+    let now = db::unix_timestamp();
+    let windows = config
+        .uptime_windows
+        .iter()
+        .map(|&hours| heartbeats::window_uptime(&reports, hours, now, interval as i64))
+        .collect();
+
+    Ok(Some(Json(HostUptime {
+        uptime: legacy.map(|uptime| uptime.uptime),
+        windows,
+    })))
 }
 
-#[get("/list-available?<hours>")]
-async fn list_available(
-    hours: u64,
+// Resolves ZeroTier IP, WAN IP or email of a host to hosts as listed by /hosts/list-available
+#[get("/search?<search..>")]
+async fn search(
+    search: HostSearch,
     pool: &State<db::AppDbPool>,
 ) -> Result<Json<Vec<HostInfo>>, ApiError> {
-    // TODO: return BAD_REQUEST if hours not passed
-    let hosts = db::get_hosts_stats(&pool.mongo, hours).await?;
+    if search.is_empty() {
+        return Err(ApiError::BadRequest(Error400::Message(
+            "Specify at least one of zt_ip, wan_ip, email or q",
+        )));
+    }
+
+    let registered_keys = |email: Option<String>| async move {
+        match email {
+            Some(email) => db::get_registered_pub_keys(&email, &pool.mongo).await,
+            None => Ok(HashSet::new()),
+        }
+    };
+    let email_keys = registered_keys(search.email.clone()).await?;
+    let q_keys = registered_keys(search.q.clone()).await?;
+
+    // Look for reports as old as `cleanup_database` keeps them
+    let hosts =
+        db::get_hosts_stats(&pool.mongo, db::RETENTION_HOURS, &HostFilter::default()).await?;
     let members = db::get_zerotier_members(&pool.mongo).await?;
 
-    Ok(Json(list_available_hosts(hosts, members).await?))
+    Ok(Json(search_hosts(
+        hosts,
+        members,
+        &search,
+        &email_keys,
+        &q_keys,
+    )))
+}
+
+// Hosts that reported within retention of `holoport_status` but not in the last <since> hours
+#[get("/silent?<since>")]
+async fn silent(
+    since: Option<u64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<SilentHosts>, ApiError> {
+    // Missing parameter would otherwise forward the request to host route
+    let since = since.ok_or(ApiError::BadRequest(Error400::Message(
+        "Specify `since` as a number of hours",
+    )))?;
+    // Look for reports as old as `cleanup_database` keeps them
+    let hosts =
+        db::get_hosts_stats(&pool.mongo, db::RETENTION_HOURS, &HostFilter::default()).await?;
+    let members = db::get_zerotier_members(&pool.mongo).await?;
+
+    let since = i64::try_from(since)
+        .unwrap_or(i64::MAX)
+        .saturating_mul(60 * 60);
+    let cutoff = db::unix_timestamp().saturating_sub(since);
+    Ok(Json(handlers::silent_hosts(hosts, &members, cutoff)))
+}
+
+// Everything known about a single host, personal data of its registrant only with `include_pii`
+#[get("/<holoport_id>?<include_pii>")]
+async fn host(
+    holoport_id: String,
+    include_pii: Option<bool>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Option<Json<HostDetail>>, ApiError> {
+    let include_pii = include_pii.unwrap_or(false);
+    // Fails on holoport id that is not a valid public key
+    let registration = db::get_host_registration(&holoport_id, include_pii, &pool.mongo).await?;
+
+    let latest = db::get_host_history(holoport_id.clone(), None, None, Some(1), None, &pool.mongo)
+        .await?
+        .inner
+        .pop();
+    let zerotier_ip = latest
+        .as_ref()
+        .and_then(|report| report.stats.zt_ip.as_deref());
+    let members = db::get_host_zerotier_members(&holoport_id, zerotier_ip, &pool.mongo).await?;
+    let uptime = db::host_uptime(holoport_id.clone(), &pool.mongo).await?;
+
+    Ok(host_detail(
+        holoport_id,
+        latest,
+        members,
+        uptime.map(|uptime| uptime.uptime),
+        registration,
+        include_pii,
+    )
+    .map(Json))
+}
+
+#[get("/<holoport_id>/history?<from>&<to>&<limit>&<cursor>")]
+async fn history(
+    holoport_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Page<Json<Vec<HostStatsRecord>>>, ApiError> {
+    Ok(
+        db::get_host_history(holoport_id, from, to, limit, cursor.as_deref(), &pool.mongo)
+            .await?
+            .map(Json),
+    )
+}
+
+#[get("/list-available?<hours>&<limit>&<cursor>&<format>&<filter..>")]
+#[allow(clippy::too_many_arguments)]
+async fn list_available(
+    hours: Option<u64>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    format: Option<ExportFormat>,
+    filter: HostFilter,
+    accept: Option<&Accept>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Page<HostInfoExport>, ApiError> {
+    let format = ExportFormat::negotiate(format, accept);
+    // Missing parameter would otherwise forward the request to host route
+    let hours = hours.ok_or(ApiError::BadRequest(Error400::Message(
+        "Specify `hours` as a number of hours",
+    )))?;
+    let hosts = db::get_hosts_stats(&pool.mongo, hours, &filter).await?;
+    let members = db::get_zerotier_members(&pool.mongo).await?;
+
+    let hosts = filter_hosts(list_available_hosts(hosts, members).await?, &filter);
+    let mut page = paginate_hosts(hosts, limit, cursor.as_deref())?;
+
+    // History of pending reboot is looked up only for hosts on the returned page
+    let pending_ids: Vec<String> = page
+        .inner
+        .iter()
+        .filter(|host| host.pending_reboot == Some(true))
+        .filter_map(|host| host.holoport_id.clone())
+        .collect();
+    let pending_since = db::get_pending_since(&pending_ids, &pool.mongo).await?;
+    set_pending_since(&mut page.inner, &pending_since);
+    Ok(page.map(|hosts| export_hosts(hosts, format)))
 }
 
 #[get("/capacity")]
-async fn capacity(pool: &State<db::AppDbPool>) -> Result<Json<Capacity>> {
-    Ok(Json(db::network_capacity(&pool.mongo).await?))
+async fn capacity(
+    pool: &State<db::AppDbPool>,
+    config: &State<AppConfig>,
+) -> Result<Json<NetworkCapacity>, ApiError> {
+    Ok(Json(
+        db::network_capacity(&pool.mongo, &config.capacity_tiers).await?,
+    ))
+}
+
+// Defaults to the last 7 days
+#[get("/capacity/history?<from>&<to>&<interval>")]
+async fn capacity_history(
+    from: Option<i64>,
+    to: Option<i64>,
+    interval: Option<i64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<Vec<CapacitySnapshot>>, ApiError> {
+    let to = to.unwrap_or_else(db::unix_timestamp);
+    let from = from.unwrap_or(to.saturating_sub(7 * 24 * 60 * 60));
+    Ok(Json(
+        db::get_capacity_history(from, to, interval, &pool.mongo).await?,
+    ))
+}
+
+#[get("/versions?<hours>")]
+async fn versions(
+    hours: Option<u64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<VersionRollout>, ApiError> {
+    let hours = hours.unwrap_or(DEFAULT_HOURS);
+    let hosts = db::get_hosts_stats(&pool.mongo, hours, &HostFilter::default()).await?;
+    let first_seen = db::get_versions_first_seen(&pool.mongo).await?;
+    Ok(Json(version_rollout(&hosts, first_seen)))
+}
+
+#[get("/pending-upgrades?<hours>")]
+async fn pending_upgrades(
+    hours: Option<u64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<PendingUpgrades>, ApiError> {
+    let hours = hours.unwrap_or(DEFAULT_HOURS);
+    let hosts = db::get_hosts_stats(&pool.mongo, hours, &HostFilter::default()).await?;
+    let pending_ids: Vec<String> = hosts
+        .iter()
+        .filter(|host| pending_reboot(&host.hpos_version, &host.channel_version) == Some(true))
+        .map(|host| host.holoport_id.clone())
+        .collect();
+    let pending_since = db::get_pending_since(&pending_ids, &pool.mongo).await?;
+    Ok(Json(handlers::pending_upgrades(hosts, &pending_since)))
+}
+
+#[get("/?<hours>")]
+async fn happs(
+    hours: Option<u64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<Vec<HappInventory>>, ApiError> {
+    let hours = hours.unwrap_or(DEFAULT_HOURS);
+    let hosts = db::get_hosts_stats(&pool.mongo, hours, &HostFilter::default()).await?;
+    Ok(Json(handlers::happ_inventory(&hosts)))
+}
+
+#[get("/<app_id>/hosts?<hours>")]
+async fn happ_hosts(
+    app_id: String,
+    hours: Option<u64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<Vec<HappHost>>, ApiError> {
+    let hours = hours.unwrap_or(DEFAULT_HOURS);
+    let hosts = db::get_hosts_stats(&pool.mongo, hours, &HostFilter::default()).await?;
+    Ok(Json(handlers::happ_hosts(hosts, &app_id)))
+}
+
+#[get("/events?<hours>&<limit>")]
+async fn happ_failures(
+    hours: u64,
+    limit: Option<i64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<Vec<HappEvent>>, ApiError> {
+    Ok(Json(
+        db::get_happ_failures(hours, limit, &pool.mongo).await?,
+    ))
+}
+
+#[get("/<holoport_id>/happs/<app_id>/events?<limit>")]
+async fn happ_events(
+    holoport_id: String,
+    app_id: String,
+    limit: Option<i64>,
+    pool: &State<db::AppDbPool>,
+) -> Result<Json<Vec<HappEvent>>, ApiError> {
+    Ok(Json(
+        db::get_happ_events(holoport_id, app_id, limit, &pool.mongo).await?,
+    ))
 }
 
-#[post("/stats", format = "application/json", data = "<stats>")]
-async fn add_host_stats(stats: HostStats, pool: &State<db::AppDbPool>) -> Result<(), ApiError> {
-    db::add_host_stats(stats, pool).await
+// Payload format is selected by the data guard from `Content-Type`, see `types::PayloadFormat`
+#[post("/stats", data = "<stats>")]
+#[allow(clippy::too_many_arguments)]
+async fn add_host_stats(
+    stats: Result<HostStats, ApiError>,
+    pool: &State<db::AppDbPool>,
+    config: &State<AppConfig>,
+    nonces: &State<NonceCache>,
+    registrations: &State<RegistrationIndex>,
+    limiter: &State<RateLimiter>,
+    queue: &State<Option<IngestQueue>>,
+    dedup: &State<Option<ReportDedup>>,
+    happ_tracker: &State<HappTracker>,
+) -> Result<(), ApiError> {
+    // Failed verification is returned as is, so that host gets the reason of rejection
+    let stats = stats?;
+    let nonce = nonces
+        .check(
+            &stats,
+            db::unix_timestamp(),
+            config.max_clock_skew,
+            config.max_clock_skew,
+            &pool.mongo,
+        )
+        .await?;
+    // Limit only verified fresh reports, so that nobody can exhaust limit of other host
+    // by replaying its reports
+    let stored = match limiter.check(&stats.holoport_id, Instant::now()) {
+        Ok(()) => {
+            db::add_host_stats(
+                stats.clone(),
+                config.registration_policy,
+                registrations,
+                queue.as_ref(),
+                dedup.as_ref(),
+                pool,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    // Host can retry a report that was not stored
+    if let Err(e) = stored {
+        if let Some(nonce) = nonce {
+            nonces.release(nonce);
+        }
+        return Err(e);
+    }
+    nonces
+        .commit(nonce.into_iter().collect(), &pool.mongo)
+        .await;
+    happ_tracker.record(vec![stats], &pool.mongo).await;
+    Ok(())
+}
+
+#[post("/stats/batch", format = "application/json", data = "<batch>")]
+#[allow(clippy::too_many_arguments)]
+async fn add_host_stats_batch(
+    batch: Result<HostStatsBatch, ApiError>,
+    pool: &State<db::AppDbPool>,
+    config: &State<AppConfig>,
+    nonces: &State<NonceCache>,
+    registrations: &State<RegistrationIndex>,
+    limiter: &State<RateLimiter>,
+    dedup: &State<Option<ReportDedup>>,
+    happ_tracker: &State<HappTracker>,
+) -> Result<Json<Vec<BatchReportResult>>, ApiError> {
+    let mut batch = batch?;
+    // Reports in a batch were queued on host while it was offline, so they may be older
+    let now = db::unix_timestamp();
+    let mut batch_nonces = HashMap::new();
+    let mut reports = Vec::with_capacity(batch.reports.len());
+    for (index, report) in batch.reports.into_iter().enumerate() {
+        let stats = match report {
+            Ok(stats) => stats,
+            Err(e) => {
+                reports.push(Err(e));
+                continue;
+            }
+        };
+        let checked = nonces
+            .check(
+                &stats,
+                now,
+                config.max_batch_report_age,
+                config.max_clock_skew,
+                &pool.mongo,
+            )
+            .await;
+        let nonce = match checked {
+            Ok(nonce) => nonce,
+            // Failure of database fails whole batch so that host retries it later
+            Err(e @ ApiError::Database(_)) => {
+                release_all(nonces, batch_nonces);
+                return Err(e);
+            }
+            Err(e) => {
+                reports.push(Err(e));
+                continue;
+            }
+        };
+        if let Some(nonce) = nonce {
+            batch_nonces.insert(index, nonce);
+        }
+        reports.push(Ok(stats));
+    }
+    // Each report takes a token, replayed reports do not use up limit of the host
+    limiter.limit_batch(&mut reports, Instant::now());
+    let limited = reports
+        .iter()
+        .enumerate()
+        .filter(|(_, report)| report.is_err());
+    for (index, _) in limited {
+        if let Some(nonce) = batch_nonces.remove(&index) {
+            nonces.release(nonce);
+        }
+    }
+    batch.reports = reports;
+    let mut reports: HashMap<usize, HostStats> = batch
+        .reports
+        .iter()
+        .enumerate()
+        .filter_map(|(index, report)| Some((index, report.as_ref().ok()?.clone())))
+        .collect();
+
+    let results = match db::add_host_stats_batch(
+        batch,
+        config.registration_policy,
+        registrations,
+        dedup.as_ref(),
+        pool,
+    )
+    .await
+    {
+        Ok(results) => results,
+        // Host can retry a batch that was not stored
+        Err(e) => {
+            release_all(nonces, batch_nonces);
+            return Err(e);
+        }
+    };
+    let mut stored = vec![];
+    for result in &results {
+        if let Some(nonce) = batch_nonces.remove(&result.index) {
+            match result.accepted {
+                true => stored.push(nonce),
+                false => nonces.release(nonce),
+            }
+        }
+    }
+    nonces.commit(stored, &pool.mongo).await;
+
+    let accepted = results
+        .iter()
+        .filter(|result| result.accepted)
+        .filter_map(|result| reports.remove(&result.index))
+        .collect();
+    happ_tracker.record(accepted, &pool.mongo).await;
+    Ok(Json(results))
 }
 
-#[launch]
-async fn rocket() -> _ {
-    rocket::build()
-        .manage(db::init_db_pool().await)
+fn release_all(nonces: &NonceCache, batch_nonces: HashMap<usize, Nonce>) {
+    batch_nonces
+        .into_values()
+        .for_each(|nonce| nonces.release(nonce));
+}
+
+// 404 if reports are written before responding, see `ingest_queue_capacity`
+#[get("/ingest")]
+async fn ingest_status(queue: &State<Option<IngestQueue>>) -> Option<Json<IngestStatus>> {
+    queue.as_ref().map(|queue| Json(queue.status()))
+}
+
+#[get("/rate-limited")]
+async fn rate_limited(limiter: &State<RateLimiter>) -> Json<RateLimitRejections> {
+    Json(limiter.rejections())
+}
+
+async fn rocket() -> Rocket<Build> {
+    let rocket = rocket::build();
+    let config: AppConfig = rocket
+        .figment()
+        .extract()
+        .expect("API configuration is invalid");
+
+    let pool = db::init_db_pool().await;
+
+    if config.capacity_snapshot_interval > 0 {
+        snapshots::spawn_capacity_snapshots(
+            pool.mongo.clone(),
+            Duration::from_secs(config.capacity_snapshot_interval),
+            config.capacity_tiers.clone(),
+        );
+    }
+
+    let registrations = RegistrationIndex::default();
+    if config.registration_refresh_interval > 0 {
+        registrations.spawn_refresh(
+            pool.mongo.clone(),
+            Duration::from_secs(config.registration_refresh_interval),
+        );
+    }
+
+    // Queue spawns its writer task right away, so it has to be created within the runtime
+    let queue = match config.ingest_queue_capacity {
+        0 => None,
+        capacity => Some(IngestQueue::new(
+            pool.mongo.clone(),
+            capacity,
+            config.ingest_batch_size,
+            Duration::from_millis(config.ingest_flush_interval),
+        )),
+    };
+
+    // Identical report arriving later than uptime computation expects one starts a new document
+    let interval = config.uptime_report_interval;
+    let dedup = config
+        .dedup_reports
+        .then(|| ReportDedup::new(interval + interval / 2));
+
+    rocket
+        .manage(queue)
+        .manage(dedup)
+        .manage(HappTracker::default())
+        .manage(NonceCache::new(
+            config.max_batch_report_age.max(config.max_clock_skew),
+            config.require_nonce,
+        ))
+        .manage(registrations)
+        .manage(RateLimiter::new(
+            config.rate_limit_per_hour,
+            config.rate_limit_burst,
+        ))
+        .manage(config)
+        .manage(pool)
         .mount("/", rocket::routes![index, cleanup])
         .mount(
             "/hosts/",
-            rocket::routes![uptime, list_available, add_host_stats],
+            rocket::routes![
+                uptime,
+                search,
+                silent,
+                host,
+                history,
+                happ_events,
+                list_available,
+                add_host_stats,
+                add_host_stats_batch,
+                rate_limited,
+                ingest_status
+            ],
+        )
+        .mount(
+            "/network/",
+            rocket::routes![capacity, capacity_history, versions, pending_upgrades],
         )
-        .mount("/network/", rocket::routes![capacity])
+        .mount("/happs/", rocket::routes![happs, happ_hosts, happ_failures])
+}
+
+#[rocket::main]
+async fn main() -> Result<(), Box<rocket::Error>> {
+    let rocket = rocket().await.ignite().await?;
+    let ingest_worker = rocket
+        .state::<Option<IngestQueue>>()
+        .and_then(|queue| queue.as_ref())
+        .and_then(|queue| queue.take_worker());
+
+    let result = rocket.launch().await;
+
+    // Rocket and the queue it managed are dropped by now,
+    // so the writer stores reports that are still queued and finishes
+    if let Some(worker) = ingest_worker {
+        let _ = worker.await;
+    }
+    result.map_err(Box::new)
 }
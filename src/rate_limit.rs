@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Header;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::types::{ApiError, Error429, HostStats, RejectReason, Rejection};
+
+// How often idle buckets are removed from memory
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Per holoport_id token bucket limiter of report submissions.
+// Each host can submit `burst` reports at once, after which it gets `per_hour` reports per hour.
+// Managed by Rocket as a State, hence Mutex
+pub struct RateLimiter {
+    // tokens refilled per second
+    rate: f64,
+    burst: f64,
+    inner: Mutex<Buckets>,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    // Rejected reports per host, kept only as long as its bucket
+    rejections: HashMap<String, u64>,
+    total_rejections: u64,
+    last_pruned: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Return type for /hosts/rate-limited endpoint
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitRejections {
+    // Number of rejected reports since API start
+    pub total: u64,
+    // Number of rejected reports per holoport_id of hosts that did not refill their bucket yet
+    pub hosts: HashMap<String, u64>,
+}
+
+impl RateLimiter {
+    /// Limiter with `per_hour` set to 0 lets all reports through
+    pub fn new(per_hour: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: f64::from(per_hour) / 3600.0,
+            burst: f64::from(burst.max(1)),
+            inner: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                rejections: HashMap::new(),
+                total_rejections: 0,
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from bucket of `holoport_id`, or returns an error telling host when
+    /// next token will be available
+    pub fn check(&self, holoport_id: &str, now: Instant) -> Result<(), ApiError> {
+        self.try_acquire(holoport_id, now)
+            .map_err(|retry_after| rate_limited(holoport_id, retry_after))
+    }
+
+    /// Takes a token from bucket of `holoport_id`, or returns number of seconds
+    /// after which next token will be available
+    pub fn try_acquire(&self, holoport_id: &str, now: Instant) -> Result<(), u64> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+
+        let mut inner = self.inner.lock().expect("rate limiter lock poisoned");
+
+        // Buckets that had enough time to refill completely are indistinguishable from new ones
+        if now.duration_since(inner.last_pruned) >= PRUNE_INTERVAL {
+            let refill_time = Duration::from_secs_f64(self.burst / self.rate);
+            let Buckets {
+                buckets,
+                rejections,
+                ..
+            } = &mut *inner;
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < refill_time);
+            // Any holoport_id can be submitted, so that counts cannot outlive buckets
+            rejections.retain(|holoport_id, _| buckets.contains_key(holoport_id));
+            inner.last_pruned = now;
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = inner
+            .buckets
+            .entry(holoport_id.to_string())
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / rate).ceil() as u64;
+        *inner.rejections.entry(holoport_id.to_string()).or_insert(0) += 1;
+        inner.total_rejections += 1;
+
+        Err(retry_after)
+    }
+
+    /// Takes a token for each valid report of a batch, so that a batch costs the same
+    /// as submitting its reports one by one. Reports of hosts out of tokens are rejected
+    pub fn limit_batch(&self, reports: &mut [Result<HostStats, ApiError>], now: Instant) {
+        for report in reports.iter_mut() {
+            let limited = match report {
+                Ok(stats) => match self.try_acquire(&stats.holoport_id, now) {
+                    Ok(()) => continue,
+                    Err(retry_after) => rate_limited(&stats.holoport_id, retry_after),
+                },
+                Err(_) => continue,
+            };
+            *report = Err(limited);
+        }
+    }
+
+    pub fn rejections(&self) -> RateLimitRejections {
+        let inner = self.inner.lock().expect("rate limiter lock poisoned");
+        RateLimitRejections {
+            total: inner.total_rejections,
+            hosts: inner.rejections.clone(),
+        }
+    }
+}
+
+/// 429 response telling host to retry in `retry_after` seconds
+pub fn rate_limited(holoport_id: &str, retry_after: u64) -> ApiError {
+    ApiError::RateLimited(Error429 {
+        inner: Rejection::new(
+            RejectReason::RateLimited,
+            format!(
+                "Host {} submits reports too often, retry in {}s.",
+                holoport_id, retry_after
+            ),
+        ),
+        retry_after: Header::new("Retry-After", retry_after.to_string()),
+    })
+}
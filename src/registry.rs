@@ -0,0 +1,108 @@
+use mongodb::bson::doc;
+use mongodb::options::AggregateOptions;
+use mongodb::Client;
+use rocket::futures::TryStreamExt;
+use rocket::response::Debug;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::db;
+use crate::types::ApiError;
+
+// In-process index of holochain encoded agent pub keys of all hosts from `opsconsoledb.registrations`.
+// It is `None` until first load succeeds, in which case lookups fall back to querying database.
+// Cloning is cheap and all clones share the same index, so that it can be refreshed from background task
+#[derive(Clone, Default)]
+pub struct RegistrationIndex {
+    keys: Arc<RwLock<Option<HashSet<String>>>>,
+}
+
+impl RegistrationIndex {
+    /// Checks whether `pub_key` belongs to a registered host. Keys missing in the index are
+    /// confirmed in database, because host could have registered after last refresh
+    pub async fn is_registered(&self, pub_key: &str, db: &Client) -> Result<bool, ApiError> {
+        if self.contains(pub_key) == Some(true) {
+            return Ok(true);
+        }
+
+        match db::verify_host(pub_key.to_string(), db).await {
+            Ok(()) => {
+                if let Some(keys) = self.keys.write().expect("lock poisoned").as_mut() {
+                    keys.insert(pub_key.to_string());
+                }
+                Ok(true)
+            }
+            Err(ApiError::MissingRecord(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns `None` while the index is not loaded, so that caller falls back to database
+    pub fn contains(&self, pub_key: &str) -> Option<bool> {
+        self.keys
+            .read()
+            .expect("lock poisoned")
+            .as_ref()
+            .map(|keys| keys.contains(pub_key))
+    }
+
+    /// Replaces content of the index with all agent pub keys currently stored in registrations
+    pub async fn refresh(&self, db: &Client) -> Result<usize, ApiError> {
+        let keys = load_registered_keys(db).await?;
+        Ok(self.replace(keys))
+    }
+
+    /// Replaces content of the index with `keys`, returns their number
+    pub fn replace(&self, keys: HashSet<String>) -> usize {
+        let count = keys.len();
+        *self.keys.write().expect("lock poisoned") = Some(keys);
+        count
+    }
+
+    /// Loads the index and keeps refreshing it every `interval` in a background task
+    pub fn spawn_refresh(&self, db: Client, interval: Duration) {
+        let index = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = index.refresh(&db).await {
+                    rocket::warn!("Failed to refresh registration index: {}", e.message());
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+}
+
+async fn load_registered_keys(db: &Client) -> Result<HashSet<String>, ApiError> {
+    let records = db
+        .database("opsconsoledb")
+        .collection::<mongodb::bson::Document>("registrations");
+
+    // Flatten registrations into a stream of bare pub keys, so that no other (personal) data is transferred
+    let pipeline = vec![
+        doc! { "$unwind": "$registrationCode" },
+        doc! { "$unwind": "$registrationCode.agentPubKeys" },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "pubKey": "$registrationCode.agentPubKeys.pubKey"
+            }
+        },
+    ];
+
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+
+    let cursor = records
+        .aggregate(pipeline, Some(options))
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)?;
+
+    cursor
+        .try_filter_map(|key| async move { Ok(key.get_str("pubKey").ok().map(String::from)) })
+        .try_collect()
+        .await
+        .map_err(Debug)
+        .map_err(ApiError::Database)
+}
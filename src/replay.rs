@@ -0,0 +1,200 @@
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::InsertManyOptions;
+use mongodb::{Client, Collection};
+use rocket::response::Debug;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::types::{ApiError, Error400, Error409, HostStats, RejectReason, Rejection};
+
+// How often in seconds expired nonces are removed from memory
+const PRUNE_INTERVAL: i64 = 60;
+
+// Remembers (holoport_id, nonce) pairs of accepted reports for `retention` seconds past
+// their timestamp, which has to be at least as long as the widest freshness window,
+// so that a report cannot be replayed via other endpoint once its nonce is forgotten.
+// Nonce is remembered only once its report is stored, so that host can retry a report that failed.
+// Nonces are also stored in `host_statistics.report_nonces`, so that they survive restart of the API
+// and are shared between its instances, memory only saves lookups of recent ones.
+// Reports without nonce are rejected only if `require_nonce` is set, so that hosts sending
+// v1 payloads keep reporting until they are upgraded.
+// Managed by Rocket as a State, hence Mutex
+pub struct NonceCache {
+    retention: i64,
+    require_nonce: bool,
+    inner: Mutex<Nonces>,
+}
+
+#[derive(Default)]
+struct Nonces {
+    // (holoport_id, nonce) -> unix timestamp in seconds after which entry can be forgotten
+    seen: HashMap<(String, String), i64>,
+    // Nonces of reports that passed `check` and are being stored
+    pending: HashSet<(String, String)>,
+    last_pruned: i64,
+}
+
+// Nonce of a report that passed `NonceCache::check`. It has to be either committed
+// once the report is stored, or released if storing fails
+#[derive(Debug)]
+pub struct Nonce {
+    key: (String, String),
+    expires: i64,
+}
+
+impl NonceCache {
+    pub fn new(retention: u64, require_nonce: bool) -> Self {
+        NonceCache {
+            retention: retention as i64,
+            require_nonce,
+            inner: Mutex::new(Nonces::default()),
+        }
+    }
+
+    /// Rejects report if its signed timestamp is more than `max_age` seconds in the past
+    /// or more than `max_skew` seconds in the future relative to `now`, or if the same host
+    /// already submitted a report with the same nonce, which includes a report being stored right now.
+    /// Otherwise returns its nonce, which is reserved until committed or released.
+    /// Report without `timestamp` or `nonce` is accepted with no nonce unless `require_nonce` is set
+    pub async fn check(
+        &self,
+        stats: &HostStats,
+        now: i64,
+        max_age: u64,
+        max_skew: u64,
+        db: &Client,
+    ) -> Result<Option<Nonce>, ApiError> {
+        let nonce = match self.check_cached(stats, now, max_age, max_skew)? {
+            Some(nonce) => nonce,
+            None => return Ok(None),
+        };
+        match is_stored(&nonce, db).await {
+            Ok(false) => Ok(Some(nonce)),
+            Ok(true) => {
+                let e = replayed(&nonce);
+                self.remember(nonce);
+                Err(e)
+            }
+            Err(e) => {
+                self.release(nonce);
+                Err(e)
+            }
+        }
+    }
+
+    /// Same as `check`, but looks only at nonces remembered in memory
+    pub fn check_cached(
+        &self,
+        stats: &HostStats,
+        now: i64,
+        max_age: u64,
+        max_skew: u64,
+    ) -> Result<Option<Nonce>, ApiError> {
+        let (timestamp, nonce) = match (stats.timestamp, &stats.nonce) {
+            (Some(timestamp), Some(nonce)) if !nonce.is_empty() => (timestamp, nonce),
+            _ if !self.require_nonce => return Ok(None),
+            _ => {
+                return Err(ApiError::InvalidPayload(Error400::Reason(Rejection::new(
+                    RejectReason::MissingNonce,
+                    "Signed payload has to contain both `timestamp` and `nonce`.".to_string(),
+                ))))
+            }
+        };
+
+        let max_age = max_age as i64;
+        let max_skew = max_skew as i64;
+        if timestamp < now - max_age || timestamp > now + max_skew {
+            return Err(ApiError::StaleTimestamp(Error400::Reason(Rejection::new(
+                RejectReason::StaleTimestamp,
+                format!(
+                    "Report timestamp {} is outside of accepted window of {}s in the past and {}s in the future from server time {}",
+                    timestamp, max_age, max_skew, now
+                ),
+            ))));
+        }
+
+        let mut nonces = self.inner.lock().expect("nonce cache lock poisoned");
+        if now - nonces.last_pruned >= PRUNE_INTERVAL {
+            nonces.seen.retain(|_, expires| *expires >= now);
+            nonces.last_pruned = now;
+        }
+
+        let key = (stats.holoport_id.clone(), nonce.clone());
+        let nonce = Nonce {
+            key,
+            expires: timestamp + self.retention,
+        };
+        if nonces.seen.contains_key(&nonce.key) || nonces.pending.contains(&nonce.key) {
+            return Err(replayed(&nonce));
+        }
+        nonces.pending.insert(nonce.key.clone());
+
+        Ok(Some(nonce))
+    }
+
+    /// Remembers nonces of stored reports both in memory and in database,
+    /// so that the reports cannot be submitted again
+    pub async fn commit(&self, nonces: Vec<Nonce>, db: &Client) {
+        if nonces.is_empty() {
+            return;
+        }
+        let docs: Vec<Document> = nonces
+            .iter()
+            .map(|nonce| {
+                doc! {
+                    "holoportId": &nonce.key.0,
+                    "nonce": &nonce.key.1,
+                    "expiresAt": DateTime::from_millis(nonce.expires.saturating_mul(1000)),
+                }
+            })
+            .collect();
+        nonces.into_iter().for_each(|nonce| self.remember(nonce));
+
+        // Reports are stored already, so failure only weakens protection of other instances
+        let options = InsertManyOptions::builder().ordered(false).build();
+        if let Err(e) = nonce_collection(db).insert_many(docs, options).await {
+            rocket::warn!("Failed to store nonces of reports: {}", e);
+        }
+    }
+
+    /// Remembers nonce of a stored report in memory only
+    pub fn remember(&self, nonce: Nonce) {
+        let mut nonces = self.inner.lock().expect("nonce cache lock poisoned");
+        nonces.pending.remove(&nonce.key);
+        nonces.seen.insert(nonce.key, nonce.expires);
+    }
+
+    /// Forgets nonce of a report that was not stored, so that host can submit it again
+    pub fn release(&self, nonce: Nonce) {
+        let mut nonces = self.inner.lock().expect("nonce cache lock poisoned");
+        nonces.pending.remove(&nonce.key);
+    }
+}
+
+fn replayed(nonce: &Nonce) -> ApiError {
+    ApiError::ReplayedReport(Error409::Reason(Rejection::new(
+        RejectReason::ReplayedNonce,
+        format!(
+            "Report with nonce {} was already submitted by host {}",
+            nonce.key.1, nonce.key.0
+        ),
+    )))
+}
+
+// Documents expire on `expiresAt` if collection has a TTL index on this field
+fn nonce_collection(db: &Client) -> Collection<Document> {
+    db.database("host_statistics").collection("report_nonces")
+}
+
+async fn is_stored(nonce: &Nonce, db: &Client) -> Result<bool, ApiError> {
+    let filter = doc! {
+        "holoportId": &nonce.key.0,
+        "nonce": &nonce.key.1,
+    };
+    nonce_collection(db)
+        .find_one(filter, None)
+        .await
+        .map(|stored| stored.is_some())
+        .map_err(Debug)
+        .map_err(ApiError::Database)
+}
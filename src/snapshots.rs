@@ -0,0 +1,22 @@
+use mongodb::Client;
+use std::time::Duration;
+
+use crate::config::CapacityTier;
+use crate::db;
+
+/// Records capacity of each holo network every `interval` in a background task
+pub fn spawn_capacity_snapshots(db: Client, interval: Duration, tiers: Vec<CapacityTier>) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let snapshot = db::network_capacity_snapshot(&db, db::unix_timestamp(), &tiers).await;
+            if let Err(e) = match snapshot {
+                Ok(snapshot) => db::add_capacity_snapshots(snapshot, &db).await,
+                Err(e) => Err(e),
+            } {
+                rocket::warn!("Failed to record capacity snapshot: {}", e.message());
+            }
+        }
+    });
+}
@@ -1,10 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// Unit tests of handler functions
 /// All those tests that require interaction with db are included
 /// in integration test suite
-use super::handlers::list_available_hosts;
-use super::types::{HostInfo, HostStats, ZerotierMember};
+use super::config::{AppConfig, CapacityTier};
+use super::db::{capacity_by_network, history_cursor, parse_history_cursor};
+use super::dedup::{BatchPlan, ReportDedup};
+use super::handlers::{
+    export_hosts, filter_hosts, happ_hosts, happ_inventory, host_detail, list_available_hosts,
+    paginate_hosts, pending_upgrades, search_hosts, silent_hosts, version_rollout,
+};
+use super::happ_tracker::app_transitions;
+use super::heartbeats::window_uptime;
+use super::rate_limit::RateLimiter;
+use super::registry::RegistrationIndex;
+use super::replay::NonceCache;
+use super::types::{
+    decode_body, verify_signed_payload, ApiError, BatchReportResult, Capacity, Error400,
+    ExportFormat, HostFilter, HostInfo, HostInfoExport, HostRegistrationInfo, HostSearch,
+    HostStats, HostStatsBatch, HostStatsRecord, PayloadFormat, RegistrationStatus, RejectReason,
+    ReportGap, SignedHostStats, ZerotierMember,
+};
+use ed25519_dalek::{Signer, SigningKey};
+use flate2::{write::GzEncoder, Compression};
+use holochain_conductor_api::AppStatusFilter;
+use mongodb::bson::{self, doc, oid::ObjectId};
+use rocket::data::ByteUnit;
+use rocket::futures::StreamExt;
+use rocket::http::Accept;
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 #[rocket::async_test]
 async fn host_found_with_no_errors() {
@@ -21,6 +46,17 @@ async fn host_found_with_no_errors() {
         hpos_app_list: Some(HashMap::new()),
         channel_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7cc1e18dd".into()),
         hpos_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7cc1e18dd".into()),
+        nonce: None,
+        registration_status: None,
+        schema_version: None,
+        cpu_load: None,
+        memory_total: None,
+        memory_used: None,
+        disk_total: None,
+        disk_used: None,
+        uptime_seconds: None,
+        kernel_version: None,
+        holochain_version: None,
     });
 
     let mut members: Vec<ZerotierMember> = vec![];
@@ -46,6 +82,18 @@ async fn host_found_with_no_errors() {
         hpos_app_list: Some(HashMap::new()),
         channel_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7cc1e18dd".into()),
         hpos_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7cc1e18dd".into()),
+        registration_status: None,
+        schema_version: None,
+        cpu_load: None,
+        memory_total: None,
+        memory_used: None,
+        disk_total: None,
+        disk_used: None,
+        uptime_seconds: None,
+        kernel_version: None,
+        holochain_version: None,
+        pending_reboot: Some(false),
+        pending_since: None,
         errors: vec![],
     }];
 
@@ -69,6 +117,17 @@ async fn host_found_mismatched_names() {
         hpos_app_list: Some(HashMap::new()),
         channel_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaaa".into()),
         hpos_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaab".into()),
+        nonce: None,
+        registration_status: None,
+        schema_version: None,
+        cpu_load: None,
+        memory_total: None,
+        memory_used: None,
+        disk_total: None,
+        disk_used: None,
+        uptime_seconds: None,
+        kernel_version: None,
+        holochain_version: None,
     });
 
     let mut members: Vec<ZerotierMember> = vec![];
@@ -95,6 +154,18 @@ async fn host_found_mismatched_names() {
             hpos_app_list: Some(HashMap::new()),
             channel_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaaa".into()),
             hpos_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaab".into()),
+            registration_status: None,
+            schema_version: None,
+            cpu_load: None,
+            memory_total: None,
+            memory_used: None,
+            disk_total: None,
+            disk_used: None,
+            uptime_seconds: None,
+            kernel_version: None,
+            holochain_version: None,
+            pending_reboot: Some(true),
+            pending_since: None,
             errors: vec!["Mismatched holoport ID between data from zerotier (5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4) and netstatsd (6avezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy58g4iy5)".into()],
         },
     ];
@@ -132,6 +203,18 @@ async fn host_not_found() {
             hpos_app_list: None,
             channel_version: None,
             hpos_version: None,
+            registration_status: None,
+            schema_version: None,
+            cpu_load: None,
+            memory_total: None,
+            memory_used: None,
+            disk_total: None,
+            disk_used: None,
+            uptime_seconds: None,
+            kernel_version: None,
+            holochain_version: None,
+            pending_reboot: None,
+            pending_since: None,
             errors: vec![
               "IP 172.26.215.31 is listed in Zerotier Central as active, but no holoport reported this IP via netstatsd within queried timeframe".to_string()
             ],
@@ -158,6 +241,17 @@ async fn zt_member_not_found() {
         hpos_app_list: Some(HashMap::new()),
         channel_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaaa".into()),
         hpos_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaab".into()),
+        nonce: None,
+        registration_status: None,
+        schema_version: None,
+        cpu_load: None,
+        memory_total: None,
+        memory_used: None,
+        disk_total: None,
+        disk_used: None,
+        uptime_seconds: None,
+        kernel_version: None,
+        holochain_version: None,
     });
 
     let members: Vec<ZerotierMember> = vec![];
@@ -177,6 +271,18 @@ async fn zt_member_not_found() {
             hpos_app_list: Some(HashMap::new()),
             channel_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaaa".into()),
             hpos_version: Some("89ec8aaef697b4741e6f0cefc4a9f8e7aaaaaab".into()),
+            registration_status: None,
+            schema_version: None,
+            cpu_load: None,
+            memory_total: None,
+            memory_used: None,
+            disk_total: None,
+            disk_used: None,
+            uptime_seconds: None,
+            kernel_version: None,
+            holochain_version: None,
+            pending_reboot: Some(true),
+            pending_since: None,
             errors: vec!["Netstatsd reported zerotier IP as 172.26.215.31 but Zerotier Central has no knowledge of it".into()],
         },
     ];
@@ -185,3 +291,1022 @@ async fn zt_member_not_found() {
 
     assert_eq!(&expected_result[..], &result[..]);
 }
+
+fn signed_report(timestamp: i64, nonce: &str) -> HostStats {
+    HostStats {
+        holoport_id: "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4".into(),
+        timestamp: Some(timestamp),
+        nonce: Some(nonce.into()),
+        ..HostStats::default()
+    }
+}
+
+#[test]
+fn replayed_nonce_is_rejected() {
+    let nonces = NonceCache::new(600, true);
+    let now = 1_700_000_000;
+
+    let nonce = nonces
+        .check_cached(&signed_report(now, "a1"), now, 300, 300)
+        .unwrap()
+        .unwrap();
+    // Report being stored cannot be submitted again
+    assert!(matches!(
+        nonces.check_cached(&signed_report(now, "a1"), now, 300, 300),
+        Err(ApiError::ReplayedReport(_))
+    ));
+    nonces.remember(nonce);
+    let nonce = nonces
+        .check_cached(&signed_report(now + 1, "b2"), now, 300, 300)
+        .unwrap()
+        .unwrap();
+    nonces.remember(nonce);
+    let replayed = nonces
+        .check_cached(&signed_report(now, "a1"), now + 10, 300, 300)
+        .unwrap_err();
+    assert!(matches!(replayed, ApiError::ReplayedReport(_)));
+    assert_eq!(replayed.reason(), Some(RejectReason::ReplayedNonce));
+}
+
+#[test]
+fn released_nonce_can_be_retried() {
+    let nonces = NonceCache::new(600, true);
+    let now = 1_700_000_000;
+
+    let nonce = nonces
+        .check_cached(&signed_report(now, "a1"), now, 300, 300)
+        .unwrap()
+        .unwrap();
+    // Storing of the report failed
+    nonces.release(nonce);
+    assert!(nonces
+        .check_cached(&signed_report(now, "a1"), now + 10, 300, 300)
+        .is_ok());
+}
+
+#[test]
+fn report_outside_of_clock_skew_window_is_rejected() {
+    let nonces = NonceCache::new(600, true);
+    let now = 1_700_000_000;
+
+    assert!(matches!(
+        nonces.check_cached(&signed_report(now - 301, "a1"), now, 300, 300),
+        Err(ApiError::StaleTimestamp(_))
+    ));
+    let stale = nonces
+        .check_cached(&signed_report(now + 301, "a2"), now, 300, 300)
+        .unwrap_err();
+    assert!(matches!(stale, ApiError::StaleTimestamp(_)));
+    assert_eq!(stale.reason(), Some(RejectReason::StaleTimestamp));
+    let missing = nonces
+        .check_cached(&HostStats::default(), now, 300, 300)
+        .unwrap_err();
+    assert!(matches!(missing, ApiError::InvalidPayload(_)));
+    assert_eq!(missing.reason(), Some(RejectReason::MissingNonce));
+}
+
+#[test]
+fn report_without_nonce_is_accepted_unless_required() {
+    let nonces = NonceCache::new(600, false);
+    let now = 1_700_000_000;
+
+    // v1 payload is accepted without replay protection
+    assert!(matches!(
+        nonces.check_cached(&HostStats::default(), now, 300, 300),
+        Ok(None)
+    ));
+    assert!(matches!(
+        nonces.check_cached(&HostStats::default(), now, 300, 300),
+        Ok(None)
+    ));
+    // Report with nonce is still checked
+    let nonce = nonces
+        .check_cached(&signed_report(now, "a1"), now, 300, 300)
+        .unwrap();
+    assert!(nonce.is_some());
+    assert!(matches!(
+        nonces.check_cached(&signed_report(now, "a1"), now, 300, 300),
+        Err(ApiError::ReplayedReport(_))
+    ));
+}
+
+fn rejection_reason(payload: &[u8], signature: &str) -> Option<RejectReason> {
+    match verify_signed_payload(payload, PayloadFormat::Json, signature) {
+        Ok(_) => None,
+        Err((_, e)) => e.reason(),
+    }
+}
+
+#[test]
+fn malformed_reports_are_rejected_with_reason() {
+    let payload =
+        br#"{"holoportId": "not a holoport id!", "timestamp": 1700000000, "nonce": "a1"}"#;
+    let signature = base64::encode([0u8; 64]);
+
+    assert_eq!(
+        rejection_reason(payload, "AAAA"),
+        Some(RejectReason::InvalidSignatureLength)
+    );
+    assert_eq!(
+        rejection_reason(payload, &signature),
+        Some(RejectReason::InvalidHoloportId)
+    );
+    assert_eq!(
+        rejection_reason(b"[]", &signature),
+        Some(RejectReason::MalformedPayload)
+    );
+    assert_eq!(
+        rejection_reason(br#"{"holoportId": "x", "schemaVersion": 3}"#, &signature),
+        Some(RejectReason::UnsupportedSchemaVersion)
+    );
+}
+
+#[test]
+fn batch_reports_are_accepted_or_rejected_one_by_one() {
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let holoport_id = base36::encode(key.verifying_key().as_bytes());
+    let payload = format!(
+        r#"{{"holoportId": "{}", "timestamp": 1700000000, "nonce": "a1"}}"#,
+        holoport_id
+    );
+    let signature = base64::encode(key.sign(payload.as_bytes()).to_bytes());
+    let other_signature = base64::encode(key.sign(b"other payload").to_bytes());
+    let body = format!(
+        r#"[
+            {{"signature": "{signature}", "payload": {payload}}},
+            {{"signature": "{other_signature}", "payload": {payload}}},
+            {{"signature": "{signature}", "payload": {{"holoportId": 42}}}}
+        ]"#,
+        signature = signature,
+        other_signature = other_signature,
+        payload = payload,
+    );
+    let signed: Vec<SignedHostStats> = serde_json::from_str(&body).unwrap();
+
+    let batch = HostStatsBatch::verify(&signed);
+    let results: Vec<BatchReportResult> = batch
+        .reports
+        .iter()
+        .enumerate()
+        .map(|(index, report)| BatchReportResult::new(index, report.as_ref()))
+        .collect();
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results[0],
+        BatchReportResult {
+            index: 0,
+            holoport_id: Some(holoport_id),
+            accepted: true,
+            reason: None,
+            error: None,
+        }
+    );
+    assert!(!results[1].accepted);
+    assert_eq!(results[1].reason, Some(RejectReason::SignatureMismatch));
+    assert!(!results[2].accepted);
+    assert_eq!(results[2].reason, Some(RejectReason::MalformedPayload));
+    assert!(results[1..].iter().all(|result| result.error.is_some()));
+}
+
+#[test]
+fn compressed_payload_is_decoded() {
+    let payload =
+        br#"{"holoportId": "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4"}"#.to_vec();
+    let mut gzip = GzEncoder::new(vec![], Compression::default());
+    gzip.write_all(&payload).unwrap();
+    let gzipped = gzip.finish().unwrap();
+    let zstd = zstd::encode_all(&payload[..], 0).unwrap();
+
+    let limit = ByteUnit::Kibibyte(1);
+    assert_eq!(
+        decode_body(Some("gzip"), gzipped, limit).ok(),
+        Some(payload.clone())
+    );
+    assert_eq!(
+        decode_body(Some("zstd"), zstd, limit).ok(),
+        Some(payload.clone())
+    );
+    assert_eq!(
+        decode_body(None, payload.clone(), limit).ok(),
+        Some(payload)
+    );
+}
+
+#[test]
+fn decompression_stops_at_limit() {
+    let bomb = zstd::encode_all(&vec![0u8; 1024 * 1024][..], 19).unwrap();
+
+    match decode_body(Some("zstd"), bomb, ByteUnit::Kibibyte(64)) {
+        Err((_, e)) => assert_eq!(e.reason(), Some(RejectReason::PayloadTooLarge)),
+        Ok(_) => panic!("Decompressed body should exceed the limit"),
+    }
+    match decode_body(Some("br"), vec![], ByteUnit::Kibibyte(64)) {
+        Err((_, e)) => assert_eq!(e.reason(), Some(RejectReason::UnsupportedEncoding)),
+        Ok(_) => panic!("Brotli should not be supported"),
+    }
+}
+
+#[test]
+fn binary_payloads_are_deserialized() {
+    let report = HostStats {
+        holoport_id: "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4".into(),
+        channel: Some("master".into()),
+        ssh_status: Some(true),
+        timestamp: Some(1700000000),
+        ..HostStats::default()
+    };
+    let mut cbor = vec![];
+    ciborium::ser::into_writer(&report, &mut cbor).unwrap();
+    let msgpack = rmp_serde::to_vec_named(&report).unwrap();
+
+    for (format, payload) in [
+        (PayloadFormat::Cbor, cbor),
+        (PayloadFormat::MsgPack, msgpack),
+    ] {
+        let decoded = format.deserialize(&payload).unwrap();
+        assert_eq!(decoded.holoport_id, report.holoport_id);
+        assert_eq!(decoded.channel, report.channel);
+        assert_eq!(decoded.ssh_status, report.ssh_status);
+        assert_eq!(decoded.timestamp, report.timestamp);
+    }
+}
+
+#[test]
+fn v2_payload_is_deserialized() {
+    let payload = br#"{
+        "schemaVersion": 2,
+        "holoportId": "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4",
+        "cpuLoad": 0.42,
+        "memoryTotal": 16000000000,
+        "memoryUsed": 4000000000,
+        "diskTotal": 1000000000000,
+        "diskUsed": 250000000000,
+        "uptimeSeconds": 86400,
+        "kernelVersion": "5.15.94",
+        "holochainVersion": "0.1.3"
+    }"#;
+
+    let stats = PayloadFormat::Json.deserialize(payload).unwrap();
+    assert_eq!(stats.schema_version, Some(2));
+    assert_eq!(stats.cpu_load, Some(0.42));
+    assert_eq!(stats.memory_used, Some(4000000000));
+    assert_eq!(stats.disk_total, Some(1000000000000));
+    assert_eq!(stats.uptime_seconds, Some(86400));
+    assert_eq!(stats.holochain_version.as_deref(), Some("0.1.3"));
+}
+
+#[test]
+fn host_is_rate_limited_after_burst() {
+    let limiter = RateLimiter::new(3600, 2);
+    let host = "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4";
+    let now = Instant::now();
+
+    assert_eq!(limiter.try_acquire(host, now), Ok(()));
+    assert_eq!(limiter.try_acquire(host, now), Ok(()));
+    assert_eq!(limiter.try_acquire(host, now), Err(1));
+    // Other hosts have their own buckets
+    assert_eq!(limiter.try_acquire("other", now), Ok(()));
+    assert_eq!(
+        limiter.try_acquire(host, now + Duration::from_secs(1)),
+        Ok(())
+    );
+
+    let rejections = limiter.rejections();
+    assert_eq!(rejections.total, 1);
+    assert_eq!(rejections.hosts.get(host), Some(&1));
+
+    // Counts of hosts with refilled buckets are pruned, total is kept
+    assert_eq!(
+        limiter.try_acquire("other", now + Duration::from_secs(120)),
+        Ok(())
+    );
+    let rejections = limiter.rejections();
+    assert_eq!(rejections.total, 1);
+    assert_eq!(rejections.hosts.get(host), None);
+}
+
+#[test]
+fn batch_takes_a_token_per_report() {
+    let limiter = RateLimiter::new(3600, 2);
+    let host = "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4";
+    let report = |holoport_id: &str| {
+        Ok(HostStats {
+            holoport_id: holoport_id.into(),
+            ..Default::default()
+        })
+    };
+    let mut reports = vec![
+        report(host),
+        Err(ApiError::BadRequest(Error400::Message("malformed"))),
+        report(host),
+        report("other"),
+        report(host),
+    ];
+
+    limiter.limit_batch(&mut reports, Instant::now());
+    let reasons: Vec<Option<RejectReason>> = reports
+        .iter()
+        .map(|report| report.as_ref().err().and_then(|e| e.reason()))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![None, None, None, None, Some(RejectReason::RateLimited)]
+    );
+    assert!(reports[1].is_err());
+    assert_eq!(limiter.rejections().hosts.get(host), Some(&1));
+}
+
+#[test]
+fn only_changed_reports_are_stored_with_dedup() {
+    let dedup = ReportDedup::new(5400);
+    let stats = HostStats {
+        holoport_id: "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4".into(),
+        channel: Some("master".into()),
+        hpos_version: Some("abc".into()),
+        timestamp: Some(12345678910),
+        cpu_load: Some(0.1),
+        ..Default::default()
+    };
+    assert_eq!(dedup.unchanged(&stats), None);
+
+    let id = ObjectId::new();
+    dedup.stored(&stats, id);
+
+    // Heartbeat fields do not make a new state
+    let heartbeat = HostStats {
+        timestamp: Some(12345679910),
+        nonce: Some("n2".into()),
+        cpu_load: Some(0.9),
+        ..stats.clone()
+    };
+    assert_eq!(dedup.unchanged(&heartbeat), Some(id));
+
+    // Identical report after host was silent for longer than the gap starts a new document
+    let returned = HostStats {
+        timestamp: Some(12345679910 + 10 * 3600),
+        ..stats.clone()
+    };
+    assert_eq!(dedup.unchanged(&returned), None);
+
+    let upgraded = HostStats {
+        hpos_version: Some("def".into()),
+        ..stats
+    };
+    assert_eq!(dedup.unchanged(&upgraded), None);
+}
+
+#[test]
+fn batch_reports_are_deduplicated_in_timestamp_order() {
+    let dedup = ReportDedup::new(5400);
+    let stats = HostStats {
+        holoport_id: "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4".into(),
+        hpos_version: Some("abc".into()),
+        timestamp: Some(1000),
+        ..Default::default()
+    };
+    let stored = ObjectId::new();
+    dedup.stored(&stats, stored);
+
+    let at = |timestamp: i64, hpos_version: &str| HostStats {
+        timestamp: Some(timestamp),
+        hpos_version: Some(hpos_version.into()),
+        ..stats.clone()
+    };
+    // Queued reports arrive out of order
+    let reports = vec![
+        at(1300, "def"),
+        at(1100, "abc"),
+        at(1400, "def"),
+        at(1200, "abc"),
+    ];
+    let ids: Vec<ObjectId> = reports.iter().map(|_| ObjectId::new()).collect();
+    let pairs: Vec<(&HostStats, ObjectId)> = reports.iter().zip(ids.iter().copied()).collect();
+
+    let plan = dedup.batch(&pairs);
+    assert_eq!(plan.refreshed, vec![(stored, 3)]);
+    assert_eq!(plan.inserted, vec![(0, Some(2))]);
+    // Later single report continues the newest state of the batch
+    assert_eq!(dedup.unchanged(&at(1500, "def")), Some(ids[0]));
+
+    // Batch older than the remembered state does not continue it nor replace it
+    let older = vec![at(900, "def")];
+    let pairs: Vec<(&HostStats, ObjectId)> = older.iter().map(|r| (r, ids[1])).collect();
+    assert_eq!(
+        dedup.batch(&pairs),
+        BatchPlan {
+            inserted: vec![(0, None)],
+            refreshed: vec![],
+        }
+    );
+    assert_eq!(dedup.unchanged(&at(1600, "def")), Some(ids[0]));
+
+    // Identical reports separated by a gap are stored separately
+    let silent = vec![at(2000, "def"), at(20000, "def")];
+    let pairs: Vec<(&HostStats, ObjectId)> = silent.iter().map(|r| (r, ObjectId::new())).collect();
+    let plan = dedup.batch(&pairs);
+    assert_eq!(plan.refreshed, vec![(ids[0], 0)]);
+    assert_eq!(plan.inserted, vec![(1, None)]);
+}
+
+#[rocket::async_test]
+async fn registration_index_falls_back_until_loaded() {
+    // Nothing listens there, so any lookup that reaches database fails
+    let db = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+        .await
+        .unwrap();
+    let index = RegistrationIndex::default();
+
+    // Not loaded yet, lookup has to go to database
+    assert_eq!(index.contains("uhCAkA"), None);
+    assert!(matches!(
+        index.is_registered("uhCAkA", &db).await,
+        Err(ApiError::Database(_))
+    ));
+
+    let keys: HashSet<String> = ["uhCAkA", "uhCAkB"].iter().map(|k| k.to_string()).collect();
+    assert_eq!(index.clone().replace(keys), 2);
+    // Clones share the same index
+    assert_eq!(index.contains("uhCAkA"), Some(true));
+    assert_eq!(index.contains("uhCAkC"), Some(false));
+    assert!(matches!(index.is_registered("uhCAkA", &db).await, Ok(true)));
+}
+
+#[test]
+fn registration_index_refresh_replaces_keys() {
+    let index = RegistrationIndex::default();
+    index.replace(["uhCAkA", "uhCAkB"].iter().map(|k| k.to_string()).collect());
+
+    assert_eq!(
+        index.replace(["uhCAkC"].iter().map(|k| k.to_string()).collect()),
+        1
+    );
+    assert_eq!(index.contains("uhCAkA"), Some(false));
+    assert_eq!(index.contains("uhCAkC"), Some(true));
+}
+
+#[test]
+fn history_cursor_is_parsed() {
+    let id = ObjectId::new();
+    let cursor = history_cursor(1690086000, id);
+    assert!(matches!(parse_history_cursor(&cursor), Ok((1690086000, parsed)) if parsed == id));
+    assert!(parse_history_cursor("1690086000").is_err());
+    assert!(parse_history_cursor("abc_64bfa2f0c2a4d1e5f6a7b8c9").is_err());
+}
+
+#[rocket::async_test]
+async fn hosts_are_filtered() {
+    let mut apps = HashMap::new();
+    apps.insert("app1".to_string(), AppStatusFilter::Running);
+    apps.insert("app2".to_string(), AppStatusFilter::Paused);
+    let hosts = vec![
+        HostStats {
+            holo_network: Some("mainNet".into()),
+            zt_ip: Some("172.26.215.30".into()),
+            holoport_id: "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4".into(),
+            hpos_app_list: Some(apps),
+            ..Default::default()
+        },
+        HostStats {
+            holo_network: Some("devNet".into()),
+            zt_ip: Some("172.26.215.31".into()),
+            holoport_id: "3kiacr9g2m2qdwt7pbqe7xmm2dw4ccc68xbujetv4zqqp7mkym".into(),
+            ..Default::default()
+        },
+    ];
+    let filtered = |filter: HostFilter| {
+        let hosts = hosts.clone();
+        async move { filter_hosts(list_available_hosts(hosts, vec![]).await.unwrap(), &filter) }
+    };
+
+    let result = filtered(HostFilter {
+        holo_network: Some("mainNet".into()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].zerotier_ip.as_deref(), Some("172.26.215.30"));
+
+    let result = filtered(HostFilter {
+        running_app: Some("app2".into()),
+        ..Default::default()
+    })
+    .await;
+    assert!(result.is_empty());
+
+    // None of the hosts is known to zerotier
+    let result = filtered(HostFilter {
+        has_errors: Some(true),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(result.len(), 2);
+}
+
+#[rocket::async_test]
+async fn hosts_are_sorted_and_paginated() {
+    let hosts: Vec<HostStats> = ["c", "a", "d", "b", "e"]
+        .iter()
+        .enumerate()
+        .map(|(i, id)| HostStats {
+            holoport_id: id.to_string(),
+            zt_ip: Some(format!("172.26.215.{}", i)),
+            ..Default::default()
+        })
+        .collect();
+    // Members without name, IP and reports share the same sort key
+    let members: Vec<ZerotierMember> = ["x", "y", "z"]
+        .iter()
+        .map(|email| ZerotierMember {
+            description: Some(email.to_string()),
+            ..Default::default()
+        })
+        .collect();
+    let hosts = list_available_hosts(hosts, members).await.unwrap();
+
+    let mut ids = vec![];
+    let mut emails = vec![];
+    let mut cursor = None;
+    loop {
+        let page = paginate_hosts(hosts.clone(), Some(2), cursor.as_deref()).unwrap();
+        assert_eq!(page.total, 8);
+        for host in page.inner {
+            match host.holoport_id {
+                Some(id) => ids.push(id),
+                None => emails.extend(host.registered_email),
+            }
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(ids, vec!["a", "b", "c", "d", "e"]);
+    emails.sort();
+    assert_eq!(emails, vec!["x", "y", "z"]);
+
+    assert!(paginate_hosts(hosts, Some(2), Some("not a cursor")).is_err());
+}
+
+#[rocket::async_test]
+async fn hosts_are_exported_as_csv() {
+    let accept: Accept = "text/csv".parse().unwrap();
+    let format = ExportFormat::negotiate(None, Some(&accept));
+    assert_eq!(format, ExportFormat::Csv);
+    assert_eq!(
+        ExportFormat::negotiate(Some(ExportFormat::Ndjson), Some(&accept)),
+        ExportFormat::Ndjson
+    );
+
+    let mut apps = HashMap::new();
+    apps.insert("app1".to_string(), AppStatusFilter::Running);
+    apps.insert("app2".to_string(), AppStatusFilter::Running);
+    apps.insert("app3".to_string(), AppStatusFilter::Paused);
+    let hosts = vec![HostStats {
+        holoport_id: "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4".into(),
+        zt_ip: Some("172.26.215.30".into()),
+        holo_network: Some("main, net".into()),
+        hpos_app_list: Some(apps),
+        ..Default::default()
+    }];
+    let hosts = list_available_hosts(hosts, vec![]).await.unwrap();
+
+    let rows: Vec<String> = match export_hosts(hosts, format) {
+        HostInfoExport::Csv(rows) => rows.collect().await,
+        _ => panic!("expected csv export"),
+    };
+    assert_eq!(rows.len(), 2);
+    assert!(rows[0].starts_with("zerotierIp,wanIp,"));
+    assert!(rows[0].ends_with(",appsPaused,errors\n"));
+    assert!(rows[1].starts_with(
+        "172.26.215.30,,,,5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4,,\"main, net\","
+    ));
+    assert!(rows[1].contains(",0,0,2,0,1,"));
+}
+
+#[test]
+fn versions_are_counted() {
+    let host = |hpos_version: &str| HostStats {
+        hpos_version: Some(hpos_version.into()),
+        channel_version: Some("a".into()),
+        channel: Some("master".into()),
+        ..Default::default()
+    };
+    let hosts = vec![host("a"), host("b"), host("b"), host("b")];
+    let mut first_seen = HashMap::new();
+    first_seen.insert(
+        (Some("b".into()), Some("a".into()), Some("master".into())),
+        1690000000,
+    );
+
+    let rollout = version_rollout(&hosts, first_seen);
+    assert_eq!(rollout.total_hosts, 4);
+    assert_eq!(rollout.versions.len(), 2);
+    assert_eq!(rollout.versions[0].hpos_version.as_deref(), Some("b"));
+    assert_eq!(rollout.versions[0].hosts, 3);
+    assert_eq!(rollout.versions[0].percentage, 75.0);
+    assert_eq!(rollout.versions[0].first_seen, Some(1690000000));
+    assert_eq!(rollout.versions[1].first_seen, None);
+}
+
+#[test]
+fn hosts_pending_reboot_are_listed() {
+    let host = |id: &str, hpos_version: &str| HostStats {
+        holoport_id: id.into(),
+        hpos_version: Some(hpos_version.into()),
+        channel_version: Some("a".into()),
+        ..Default::default()
+    };
+    let hosts = vec![
+        host("1", "a"),
+        host("2", "b"),
+        host("3", "b"),
+        host("4", "c"),
+    ];
+    let mut pending_since = HashMap::new();
+    pending_since.insert("3".to_string(), 1690000000);
+    pending_since.insert("4".to_string(), 1680000000);
+
+    let pending = pending_upgrades(hosts, &pending_since);
+    assert_eq!(pending.total_hosts, 4);
+    assert_eq!(pending.pending_hosts, 3);
+    let ids: Vec<&str> = pending
+        .hosts
+        .iter()
+        .map(|host| host.holoport_id.as_str())
+        .collect();
+    assert_eq!(ids, vec!["4", "3", "2"]);
+}
+
+#[test]
+fn happs_are_counted_by_status() {
+    let host = |id: &str, apps: Vec<(&str, AppStatusFilter)>| HostStats {
+        holoport_id: id.into(),
+        hpos_app_list: Some(
+            apps.into_iter()
+                .map(|(app_id, status)| (app_id.to_string(), status))
+                .collect(),
+        ),
+        ..Default::default()
+    };
+    let hosts = vec![
+        host("1", vec![("app1", AppStatusFilter::Running)]),
+        host(
+            "2",
+            vec![
+                ("app1", AppStatusFilter::Paused),
+                ("app2", AppStatusFilter::Disabled),
+            ],
+        ),
+        HostStats::default(),
+    ];
+
+    let inventory = happ_inventory(&hosts);
+    assert_eq!(inventory.len(), 2);
+    assert_eq!(inventory[0].app_id, "app1");
+    assert_eq!(
+        (
+            inventory[0].hosts,
+            inventory[0].running,
+            inventory[0].paused
+        ),
+        (2, 1, 1)
+    );
+    assert_eq!((inventory[1].hosts, inventory[1].disabled), (1, 1));
+
+    let app_hosts = happ_hosts(hosts, "app2");
+    assert_eq!(app_hosts.len(), 1);
+    assert_eq!(app_hosts[0].holoport_id, "2");
+    assert!(matches!(app_hosts[0].status, AppStatusFilter::Disabled));
+}
+
+#[test]
+fn happ_transitions_are_derived() {
+    let mut previous = HashMap::new();
+    previous.insert("app1".to_string(), AppStatusFilter::Running);
+    previous.insert("app2".to_string(), AppStatusFilter::Running);
+    previous.insert("app3".to_string(), AppStatusFilter::Running);
+    let mut current = HashMap::new();
+    current.insert("app1".to_string(), AppStatusFilter::Running);
+    current.insert("app2".to_string(), AppStatusFilter::Paused);
+    current.insert("app4".to_string(), AppStatusFilter::Enabled);
+
+    let events = app_transitions("host", 1690000000, &previous, &current);
+    let events: Vec<(&str, Option<String>, Option<String>)> = events
+        .iter()
+        .map(|event| {
+            (
+                event.app_id.as_str(),
+                event.from.as_ref().map(|status| format!("{:?}", status)),
+                event.to.as_ref().map(|status| format!("{:?}", status)),
+            )
+        })
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            ("app2", Some("Running".into()), Some("Paused".into())),
+            ("app3", Some("Running".into()), None),
+            ("app4", None, Some("Enabled".into())),
+        ]
+    );
+}
+
+#[test]
+fn uptime_windows_longer_than_retention_are_rejected() {
+    let config: AppConfig = serde_json::from_str(r#"{"uptime_windows": [24, 336]}"#).unwrap();
+    assert_eq!(config.uptime_windows, vec![24, 336]);
+    assert!(serde_json::from_str::<AppConfig>(r#"{"uptime_windows": [24, 720]}"#).is_err());
+}
+
+#[test]
+fn uptime_is_computed_from_report_gaps() {
+    let hour = 60 * 60;
+    let now = 1690000000;
+    let start = now - 24 * hour;
+    // Hourly reports over the whole day, a few seconds late
+    let reports: Vec<(i64, i64)> = (0..25)
+        .map(|i| start - hour + i * hour + 30)
+        .map(|t| (t, t))
+        .collect();
+    let uptime = window_uptime(&reports, 24, now, hour);
+    assert_eq!(uptime.uptime, 1.0);
+    assert_eq!(uptime.reports, 24);
+    assert!(uptime.gaps.is_empty());
+
+    // Host stopped reporting for 6 hours
+    let reports: Vec<(i64, i64)> = reports
+        .into_iter()
+        .filter(|(t, _)| *t < start + 10 * hour || *t > start + 16 * hour)
+        .collect();
+    let uptime = window_uptime(&reports, 24, now, hour);
+    assert_eq!(
+        uptime.gaps,
+        vec![ReportGap {
+            from: start + 9 * hour + 30 + hour + hour / 2,
+            to: start + 16 * hour + 30,
+        }]
+    );
+    assert!((uptime.uptime - 18.5 / 24.0).abs() < 1e-9);
+
+    // Deduplicated report covers time until it was last seen
+    let uptime = window_uptime(&[(start, now)], 24, now, hour);
+    assert_eq!(uptime.uptime, 1.0);
+}
+
+#[test]
+fn capacity_is_split_by_network() {
+    let performance = |name: &str, uptime: f64| {
+        bson::from_document(doc! {
+            "_id": ObjectId::new(),
+            "name": name,
+            "description": "",
+            "zt_ipaddress": "172.26.215.30",
+            "created_at": 1690000000i64,
+            "uptime": uptime,
+        })
+        .unwrap()
+    };
+    let performances = vec![
+        performance("1", 0.95),
+        performance("2", 0.6),
+        performance("3", 0.2),
+    ];
+    let mut hosts = HashMap::new();
+    hosts.insert(
+        "1".to_string(),
+        (
+            Some("mainNet".to_string()),
+            Some("holoport-plus".to_string()),
+        ),
+    );
+    hosts.insert(
+        "2".to_string(),
+        (Some("mainNet".to_string()), Some("holoport".to_string())),
+    );
+    let tiers = vec![
+        CapacityTier::new("read_only", 0.5),
+        CapacityTier::new("source_chain", 0.9),
+        CapacityTier {
+            holoport_models: Some(vec!["holoport".to_string()]),
+            ..CapacityTier::new("holoport_read_only", 0.5)
+        },
+    ];
+    let capacity = |total_hosts, tiers: [u64; 3]| Capacity {
+        total_hosts,
+        tiers: ["read_only", "source_chain", "holoport_read_only"]
+            .iter()
+            .map(|name| name.to_string())
+            .zip(tiers)
+            .collect(),
+    };
+
+    let capacities = capacity_by_network(&performances, &hosts, &tiers);
+    assert_eq!(
+        capacities.get(&Some("mainNet".to_string())),
+        Some(&capacity(2, [2, 1, 1]))
+    );
+    // Host of unknown model is not counted in a tier limited to some models
+    assert_eq!(capacities.get(&None), Some(&capacity(1, [0, 0, 0])));
+
+    // Tiers are serialized next to total_hosts
+    assert_eq!(
+        serde_json::to_value(capacity(1, [1, 0, 0])).unwrap(),
+        serde_json::json!({
+            "total_hosts": 1,
+            "read_only": 1,
+            "source_chain": 0,
+            "holoport_read_only": 0
+        })
+    );
+}
+
+#[test]
+fn host_detail_merges_sources() {
+    let registration = || HostRegistrationInfo {
+        status: RegistrationStatus::Registered,
+        given_names: None,
+        last_name: None,
+        email: None,
+    };
+    let member = |ip: &str| ZerotierMember {
+        last_online: 1690000000000,
+        zerotier_ip: Some(ip.into()),
+        physical_address: Some("1.2.3.4".into()),
+        name: Some("hp1".into()),
+        description: Some("host@example.com".into()),
+    };
+    let report = HostStatsRecord {
+        stats: HostStats {
+            holoport_id: "hp1".into(),
+            zt_ip: Some("10.0.0.1".into()),
+            timestamp: Some(1690000000),
+            ..Default::default()
+        },
+        last_seen: None,
+    };
+    let members = vec![member("10.0.0.2"), member("10.0.0.1")];
+
+    let detail = host_detail(
+        "hp1".into(),
+        Some(report.clone()),
+        members.clone(),
+        Some(0.9),
+        registration(),
+        false,
+    )
+    .unwrap();
+    let zerotier = detail.zerotier.unwrap();
+    assert_eq!(zerotier.zerotier_ip, Some("10.0.0.1".into()));
+    assert_eq!(zerotier.description, None);
+    // Stale member with another IP is reported as in /hosts/list-available
+    assert_eq!(detail.errors.len(), 1);
+    assert!(detail.errors[0].contains("10.0.0.2"));
+
+    let detail = host_detail(
+        "hp1".into(),
+        Some(report),
+        members,
+        None,
+        registration(),
+        true,
+    )
+    .unwrap();
+    assert_eq!(
+        detail.zerotier.unwrap().description,
+        Some("host@example.com".into())
+    );
+
+    assert!(host_detail("hp1".into(), None, vec![], None, registration(), false).is_none());
+}
+
+#[test]
+fn hosts_are_searched() {
+    let holoport_id = "5zvezgwyz5robqc9s20n9655be0ot9vxmgqwm8g4iy5ite9a4";
+    let hosts = vec![HostStats {
+        holoport_id: holoport_id.into(),
+        zt_ip: Some("172.26.215.30".into()),
+        wan_ip: Some("5.6.7.8".into()),
+        ..Default::default()
+    }];
+    let member = |ip: &str, name: &str, email: &str| ZerotierMember {
+        last_online: 1690000000000,
+        zerotier_ip: Some(ip.into()),
+        physical_address: Some("1.2.3.4/9993".into()),
+        name: Some(name.into()),
+        description: Some(email.into()),
+    };
+    let members = vec![
+        member("172.26.215.30", holoport_id, "Host@Example.com"),
+        member("172.26.215.31", "other", "other@example.com"),
+    ];
+
+    let search = |search: HostSearch| {
+        search_hosts(
+            hosts.clone(),
+            members.clone(),
+            &search,
+            &HashSet::new(),
+            &HashSet::new(),
+        )
+        .into_iter()
+        .map(|host| host.zerotier_ip.unwrap())
+        .collect::<Vec<_>>()
+    };
+    let query =
+        |zt_ip: Option<&str>, wan_ip: Option<&str>, email: Option<&str>, q: Option<&str>| {
+            HostSearch {
+                zt_ip: zt_ip.map(String::from),
+                wan_ip: wan_ip.map(String::from),
+                email: email.map(String::from),
+                q: q.map(String::from),
+            }
+        };
+
+    assert_eq!(
+        search(query(Some("172.26.215.30"), None, None, None)),
+        vec!["172.26.215.30"]
+    );
+    // WAN IP of ZeroTier member is listed with a port, host reports its own
+    assert_eq!(
+        search(query(None, Some("1.2.3.4"), None, None)),
+        vec!["172.26.215.31", "172.26.215.30"]
+    );
+    assert!(search(query(None, Some("1.2.3"), None, None)).is_empty());
+    assert_eq!(
+        search(query(None, Some("5.6.7.8"), None, None)),
+        vec!["172.26.215.30"]
+    );
+    assert_eq!(
+        search(query(None, None, Some("host@example.com"), None)),
+        vec!["172.26.215.30"]
+    );
+    assert_eq!(
+        search(query(None, None, None, Some(holoport_id))),
+        vec!["172.26.215.30"]
+    );
+    assert_eq!(
+        search(query(None, None, None, Some("other@example.com"))),
+        vec!["172.26.215.31"]
+    );
+    assert!(search(query(
+        Some("172.26.215.30"),
+        None,
+        Some("other@example.com"),
+        None
+    ))
+    .is_empty());
+}
+
+#[test]
+fn silent_hosts_are_listed() {
+    let host = |id: &str, zt_ip: &str, timestamp: i64| HostStats {
+        holoport_id: id.into(),
+        zt_ip: Some(zt_ip.into()),
+        timestamp: Some(timestamp),
+        hpos_version: Some("a".into()),
+        ..Default::default()
+    };
+    let hosts = vec![
+        host("1", "172.26.215.1", 1690000000),
+        host("2", "172.26.215.2", 1680000000),
+        host("3", "172.26.215.3", 1685000000),
+    ];
+    let members = vec![
+        ZerotierMember {
+            last_online: 1690000000000,
+            zerotier_ip: Some("172.26.215.2".into()),
+            ..Default::default()
+        },
+        // Member found by name after host changed its IP
+        ZerotierMember {
+            last_online: 1686000000000,
+            zerotier_ip: Some("172.26.215.4".into()),
+            name: Some("3".into()),
+            ..Default::default()
+        },
+    ];
+
+    let silent = silent_hosts(hosts, &members, 1689000000);
+    assert_eq!(silent.total_hosts, 3);
+    assert_eq!(silent.silent_hosts, 2);
+    let last_seen: Vec<(&str, Option<i64>, Option<i64>)> = silent
+        .hosts
+        .iter()
+        .map(|host| {
+            (
+                host.holoport_id.as_str(),
+                host.last_netstatsd_reported,
+                host.last_zerotier_online,
+            )
+        })
+        .collect();
+    assert_eq!(
+        last_seen,
+        vec![
+            ("2", Some(1680000000), Some(1690000000000)),
+            ("3", Some(1685000000), Some(1686000000000)),
+        ]
+    );
+    assert_eq!(silent.hosts[0].hpos_version, Some("a".into()));
+}
@@ -1,14 +1,21 @@
 use ed25519_dalek::Signature;
+use flate2::read::GzDecoder;
 use rocket::data::{self, Data, FromData};
-use rocket::http::{Method, Status};
+use rocket::form::{FromForm, FromFormField};
+use rocket::futures::stream::BoxStream;
+use rocket::http::{Accept, MediaType};
+use rocket::http::{ContentType, Header, Method, Status};
 use rocket::outcome::Outcome::*;
 use rocket::request::Request;
+use rocket::response::stream::TextStream;
 use rocket::{
-    response::Responder,
+    response::{self, Responder},
     serde::{Deserialize, Serialize},
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
+use std::io::Read;
+use zstd::stream::read::Decoder as ZstdDecoder;
 
 use holochain_conductor_api::AppStatusFilter;
 use holochain_types::app::InstalledAppId;
@@ -16,7 +23,10 @@ use holochain_types::app::InstalledAppId;
 use bson::oid::ObjectId;
 use mongodb::{bson, error::Error};
 use rocket::response::Debug;
+use rocket::serde::json::Json;
+use serde_json::value::RawValue;
 
+use super::config::CapacityTier;
 use super::db;
 
 // [rocket::response::Debug](https://api.rocket.rs/v0.5-rc/rocket/response/struct.Debug.html) implements Responder to Error
@@ -30,14 +40,17 @@ pub type Error500 = Debug<Error>;
 pub enum Error400 {
     Info(String),
     Message(&'static str),
+    Reason(Json<Rejection>),
 }
 
 #[derive(Responder, Debug)]
 #[response(status = 401)]
-
+// Disable warning if any type within 401 enum is unused
+#[allow(dead_code)]
 pub enum Error401 {
     Info(String),
     Message(&'static str),
+    Reason(Json<Rejection>),
 }
 
 #[derive(Responder, Debug)]
@@ -49,6 +62,78 @@ pub enum Error404 {
     Message(&'static str),
 }
 
+#[derive(Responder, Debug)]
+#[response(status = 409)]
+#[allow(dead_code)]
+pub enum Error409 {
+    Info(String),
+    Message(&'static str),
+    Reason(Json<Rejection>),
+}
+
+#[derive(Responder, Debug)]
+#[response(status = 413)]
+pub enum Error413 {
+    Reason(Json<Rejection>),
+}
+
+#[derive(Responder, Debug)]
+#[response(status = 415)]
+pub enum Error415 {
+    Reason(Json<Rejection>),
+}
+
+#[derive(Responder, Debug)]
+#[response(status = 429)]
+pub struct Error429 {
+    pub inner: Json<Rejection>,
+    pub retry_after: Header<'static>,
+}
+
+#[derive(Responder, Debug)]
+#[response(status = 503)]
+pub enum Error503 {
+    Reason(Json<Rejection>),
+}
+
+// Body of a response to a rejected submission. `reason` is stable and meant to be matched on by hosts,
+// `message` is meant for humans
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Rejection {
+    pub reason: RejectReason,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    PayloadTooLarge,
+    UnreadableBody,
+    UnsupportedEncoding,
+    MalformedEncoding,
+    UnsupportedContentType,
+    MalformedPayload,
+    UnsupportedSchemaVersion,
+    MissingSignature,
+    MalformedSignature,
+    InvalidSignatureLength,
+    SignatureMismatch,
+    InvalidHoloportId,
+    RateLimited,
+    QueueFull,
+    MissingNonce,
+    StaleTimestamp,
+    ReplayedNonce,
+}
+
+impl Rejection {
+    pub fn new(reason: RejectReason, message: String) -> Json<Rejection> {
+        Json(Rejection { reason, message })
+    }
+}
+
 #[derive(Responder, Debug)]
 pub enum ApiError {
     BadRequest(Error400),
@@ -57,35 +142,265 @@ pub enum ApiError {
     MissingRecord(Error404),
     MissingSignature(Error401),
     InvalidSignature(Error401),
+    ReplayedReport(Error409),
+    PayloadTooLarge(Error413),
+    InvalidHoloportId(Error400),
+    StaleTimestamp(Error400),
+    UnsupportedEncoding(Error415),
+    UnsupportedContentType(Error415),
+    RateLimited(Error429),
+    ServiceUnavailable(Error503),
 }
 
-// Return type for /network/capacity endpoint
-#[derive(Serialize, Deserialize)]
+impl ApiError {
+    /// Human readable description of an error, used where error is reported
+    /// inside of a response body rather than as a response itself
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest(e)
+            | ApiError::InvalidPayload(e)
+            | ApiError::InvalidHoloportId(e)
+            | ApiError::StaleTimestamp(e) => match e {
+                Error400::Info(s) => s.clone(),
+                Error400::Message(s) => s.to_string(),
+                Error400::Reason(r) => r.message.clone(),
+            },
+            ApiError::MissingSignature(e) | ApiError::InvalidSignature(e) => match e {
+                Error401::Info(s) => s.clone(),
+                Error401::Message(s) => s.to_string(),
+                Error401::Reason(r) => r.message.clone(),
+            },
+            ApiError::MissingRecord(e) => match e {
+                Error404::Info(s) => s.clone(),
+                Error404::Message(s) => s.to_string(),
+            },
+            ApiError::ReplayedReport(e) => match e {
+                Error409::Info(s) => s.clone(),
+                Error409::Message(s) => s.to_string(),
+                Error409::Reason(r) => r.message.clone(),
+            },
+            ApiError::PayloadTooLarge(Error413::Reason(r)) => r.message.clone(),
+            ApiError::UnsupportedEncoding(Error415::Reason(r))
+            | ApiError::UnsupportedContentType(Error415::Reason(r)) => r.message.clone(),
+            ApiError::RateLimited(e) => e.inner.message.clone(),
+            ApiError::ServiceUnavailable(Error503::Reason(r)) => r.message.clone(),
+            ApiError::Database(e) => format!("{:?}", e.0),
+        }
+    }
+
+    /// Machine readable reason of an error, if there is one
+    pub fn reason(&self) -> Option<RejectReason> {
+        match self {
+            ApiError::BadRequest(Error400::Reason(r))
+            | ApiError::InvalidPayload(Error400::Reason(r))
+            | ApiError::InvalidHoloportId(Error400::Reason(r))
+            | ApiError::StaleTimestamp(Error400::Reason(r))
+            | ApiError::MissingSignature(Error401::Reason(r))
+            | ApiError::InvalidSignature(Error401::Reason(r))
+            | ApiError::ReplayedReport(Error409::Reason(r))
+            | ApiError::PayloadTooLarge(Error413::Reason(r))
+            | ApiError::UnsupportedEncoding(Error415::Reason(r))
+            | ApiError::UnsupportedContentType(Error415::Reason(r)) => Some(r.reason),
+            ApiError::RateLimited(e) => Some(e.inner.reason),
+            ApiError::ServiceUnavailable(Error503::Reason(r)) => Some(r.reason),
+            _ => None,
+        }
+    }
+}
+
+// Capacity of a group of hosts
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
 #[serde(crate = "rocket::serde")]
 pub struct Capacity {
-    pub total_hosts: u16,
-    pub read_only: u16,
-    pub source_chain: u16,
+    pub total_hosts: u64,
+    // Number of hosts in each of configured `capacity_tiers`, serialized as fields next to `total_hosts`
+    #[serde(flatten)]
+    pub tiers: BTreeMap<String, u64>,
 }
 
 impl Capacity {
-    pub fn add_host(&mut self, uptime: f32) {
+    // Empty capacity listing all the `tiers`
+    pub fn new(tiers: &[CapacityTier]) -> Self {
+        Capacity {
+            total_hosts: 0,
+            tiers: tiers.iter().map(|tier| (tier.name.clone(), 0)).collect(),
+        }
+    }
+
+    pub fn add_host(
+        &mut self,
+        uptime: f32,
+        holoport_model: Option<&str>,
+        holo_network: Option<&str>,
+        tiers: &[CapacityTier],
+    ) {
         self.total_hosts += 1;
-        if uptime >= 0.5 {
-            self.read_only += 1
-        };
-        if uptime >= 0.9 {
-            self.source_chain += 1
-        };
+        for tier in tiers {
+            if tier.includes(uptime, holoport_model, holo_network) {
+                *self.tiers.entry(tier.name.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Capacity) {
+        self.total_hosts += other.total_hosts;
+        for (name, hosts) in &other.tiers {
+            *self.tiers.entry(name.clone()).or_default() += hosts;
+        }
+    }
+}
+
+// Return type for /network/capacity endpoint, capacity of all hosts
+// followed by capacity of each holo network
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NetworkCapacity {
+    #[serde(flatten)]
+    pub total: Capacity,
+    // Hosts that have not reported their network are under "unknown"
+    pub networks: BTreeMap<String, Capacity>,
+}
+
+// Return type for /network/versions endpoint
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct VersionRollout {
+    pub total_hosts: u64,
+    // Sorted by number of hosts, descending
+    pub versions: Vec<VersionCount>,
+}
+
+// Number of hosts whose latest report has given combination of versions and channel
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct VersionCount {
+    pub hpos_version: Option<String>,
+    pub channel_version: Option<String>,
+    pub channel: Option<String>,
+    pub hosts: u64,
+    // Percentage of all hosts, 0 - 100
+    pub percentage: f64,
+    // Timestamp of the first report with this combination still stored in `holoport_status`
+    pub first_seen: Option<i64>,
+}
+
+// Key of `VersionCount`: hpos_version, channel_version and channel
+pub type VersionKey = (Option<String>, Option<String>, Option<String>);
+
+// Return type for /network/pending-upgrades endpoint
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PendingUpgrades {
+    pub total_hosts: u64,
+    pub pending_hosts: u64,
+    // Sorted by pending_since, the longest pending first
+    pub hosts: Vec<PendingUpgrade>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct PendingUpgrade {
+    pub holoport_id: String,
+    pub channel: Option<String>,
+    pub hpos_version: Option<String>,
+    pub channel_version: Option<String>,
+    pub pending_since: Option<i64>,
+}
+
+// Return type for /hosts/silent endpoint
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SilentHosts {
+    // Hosts that reported within retention of `holoport_status`
+    pub total_hosts: u64,
+    pub silent_hosts: u64,
+    // Sorted by last_netstatsd_reported, the longest silent first
+    pub hosts: Vec<SilentHost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct SilentHost {
+    pub holoport_id: String,
+    pub last_netstatsd_reported: Option<i64>,
+    // None if ZeroTier has never seen the host or does not know it
+    pub last_zerotier_online: Option<i64>,
+    pub zerotier_ip: Option<String>,
+    pub holo_network: Option<String>,
+    pub channel: Option<String>,
+    pub hpos_version: Option<String>,
+    pub channel_version: Option<String>,
+    pub holochain_version: Option<String>,
+}
+
+// Return type for /happs endpoint, number of hosts with the app installed in each status
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct HappInventory {
+    pub app_id: InstalledAppId,
+    pub hosts: u64,
+    pub running: u64,
+    pub paused: u64,
+    pub disabled: u64,
+    pub enabled: u64,
+    pub stopped: u64,
+}
+
+impl HappInventory {
+    pub fn add_host(&mut self, status: &AppStatusFilter) {
+        self.hosts += 1;
+        match status {
+            AppStatusFilter::Running => self.running += 1,
+            AppStatusFilter::Paused => self.paused += 1,
+            AppStatusFilter::Disabled => self.disabled += 1,
+            AppStatusFilter::Enabled => self.enabled += 1,
+            AppStatusFilter::Stopped => self.stopped += 1,
+        }
     }
 }
 
+// Return type for /happs/<app_id>/hosts endpoint
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HappHost {
+    pub holoport_id: String,
+    pub status: AppStatusFilter,
+    pub last_netstatsd_reported: Option<i64>,
+}
+
+// Change of status of an app on a host between two consecutive reports
+// Data schema in collection `host_statistics.happ_events`, returned by /hosts/<holoport_id>/happs/<app_id>/events
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct HappEvent {
+    pub holoport_id: String,
+    pub app_id: InstalledAppId,
+    // None when app was installed
+    pub from: Option<AppStatusFilter>,
+    // None when app was removed
+    pub to: Option<AppStatusFilter>,
+    // Timestamp of the report in which the new status was reported
+    pub timestamp: i64,
+}
+
+// Capacity of hosts of a single holo network at a point in time
+// Data schema in collection `host_statistics.capacity_snapshots`, returned by /network/capacity/history
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct CapacitySnapshot {
+    pub timestamp: i64,
+    // None for hosts that have not reported their network
+    pub holo_network: Option<String>,
+    pub capacity: Capacity,
+}
+
 // Data schema in `performance_summary` collection
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Performance {
     _id: ObjectId,
-    name: String,
+    pub name: String,
     description: String,
     #[serde(rename = "physicalAddress")]
     physical_address: Option<String>,
@@ -101,6 +416,35 @@ pub struct Uptime {
     pub uptime: f32,
 }
 
+// Return type for /hosts/<name>/uptime endpoint
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HostUptime {
+    // Value precomputed in `performance_summary`, None if host is missing there
+    pub uptime: Option<f32>,
+    // Uptime computed from reports of the host in `holoport_status`, one per configured window
+    pub windows: Vec<WindowUptime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct WindowUptime {
+    pub hours: u64,
+    // Share of the window, 0 - 1
+    pub uptime: f64,
+    // Number of reports made within the window
+    pub reports: u64,
+    pub gaps: Vec<ReportGap>,
+}
+
+// Period of time in which host was expected to report but did not
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ReportGap {
+    pub from: i64,
+    pub to: i64,
+}
+
 // Data schema in `holoports_assignment` collection
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -110,7 +454,7 @@ pub struct Assignment {
 }
 
 // Return type for /list-available endpoint
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(crate = "rocket::serde")]
 #[serde(rename_all = "camelCase")]
 pub struct HostInfo {
@@ -127,6 +471,20 @@ pub struct HostInfo {
     pub hpos_app_list: Option<HashMap<InstalledAppId, AppStatusFilter>>,
     pub channel_version: Option<String>,
     pub hpos_version: Option<String>,
+    pub registration_status: Option<RegistrationStatus>,
+    pub schema_version: Option<u8>,
+    pub cpu_load: Option<f64>,
+    pub memory_total: Option<i64>,
+    pub memory_used: Option<i64>,
+    pub disk_total: Option<i64>,
+    pub disk_used: Option<i64>,
+    pub uptime_seconds: Option<i64>,
+    pub kernel_version: Option<String>,
+    pub holochain_version: Option<String>,
+    // HPOS has downloaded `hpos_version` but still runs `channel_version`, None if either is unknown
+    pub pending_reboot: Option<bool>,
+    // Timestamp of the first report with pending reboot, counted since host last reported matching versions
+    pub pending_since: Option<i64>,
     pub errors: Vec<String>,
 }
 
@@ -145,6 +503,18 @@ impl PartialEq for HostInfo {
             && is_hashmap_equal(&self.hpos_app_list, &other.hpos_app_list)
             && self.channel_version == other.channel_version
             && self.hpos_version == other.hpos_version
+            && self.registration_status == other.registration_status
+            && self.schema_version == other.schema_version
+            && self.cpu_load == other.cpu_load
+            && self.memory_total == other.memory_total
+            && self.memory_used == other.memory_used
+            && self.disk_total == other.disk_total
+            && self.disk_used == other.disk_used
+            && self.uptime_seconds == other.uptime_seconds
+            && self.kernel_version == other.kernel_version
+            && self.holochain_version == other.holochain_version
+            && self.pending_reboot == other.pending_reboot
+            && self.pending_since == other.pending_since
             && self.errors == other.errors
     }
 }
@@ -171,6 +541,38 @@ fn keys_match(
     map1.len() == map2.len() && map1.keys().all(|k| map2.contains_key(k))
 }
 
+// Query parameters of /hosts/list-available narrowing down returned hosts.
+// Every filter applies to the latest report of a host, unset filters match all hosts
+#[derive(FromForm, Default, Debug)]
+pub struct HostFilter {
+    pub holo_network: Option<String>,
+    pub channel: Option<String>,
+    pub holoport_model: Option<String>,
+    pub ssh_status: Option<bool>,
+    pub hpos_version: Option<String>,
+    pub has_errors: Option<bool>,
+    // Id of an app that has to be in status `running`
+    pub running_app: Option<InstalledAppId>,
+}
+
+// Query parameters of /hosts/search, a host has to match all the set ones
+#[derive(FromForm, Default, Debug)]
+pub struct HostSearch {
+    pub zt_ip: Option<String>,
+    // Matches also physical address of ZeroTier member with a port, e.g. `1.2.3.4/9993`
+    pub wan_ip: Option<String>,
+    // Email in ZeroTier member description or in registration of the host, case insensitive
+    pub email: Option<String>,
+    // Matches any of the above or holoport id
+    pub q: Option<String>,
+}
+
+impl HostSearch {
+    pub fn is_empty(&self) -> bool {
+        self.zt_ip.is_none() && self.wan_ip.is_none() && self.email.is_none() && self.q.is_none()
+    }
+}
+
 // Input type for /hosts/stats endpoint
 // Data schema in collection `host_statistics.holoport_status`
 // Note: We wrap each field value in Option<T> because if the HPOS `netstatd` fails to collect data, it will send null in failed field.
@@ -189,6 +591,138 @@ pub struct HostStats {
     pub hpos_app_list: Option<HashMap<InstalledAppId, AppStatusFilter>>,
     pub channel_version: Option<String>,
     pub hpos_version: Option<String>,
+    // Random value chosen by host for each report, signed together with `timestamp` to prevent replays
+    pub nonce: Option<String>,
+    // Set by API server on ingest according to `registration_policy`, value sent by host is ignored
+    pub registration_status: Option<RegistrationStatus>,
+    // Version of this schema, missing in reports of hosts that predate versioning (v1)
+    pub schema_version: Option<u8>,
+    // Fields added in v2:
+    // 1 minute load average
+    pub cpu_load: Option<f64>,
+    // Memory and disk space of root filesystem in bytes
+    pub memory_total: Option<i64>,
+    pub memory_used: Option<i64>,
+    pub disk_total: Option<i64>,
+    pub disk_used: Option<i64>,
+    pub uptime_seconds: Option<i64>,
+    pub kernel_version: Option<String>,
+    // Version of holochain conductor run by HPOS
+    pub holochain_version: Option<String>,
+}
+
+// Latest version of `HostStats` schema accepted by the API
+pub const HOST_STATS_SCHEMA_VERSION: u8 = 2;
+
+// Outcome of a lookup of host in `opsconsoledb.registrations` at the time of its report
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Hash)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub enum RegistrationStatus {
+    Registered,
+    Unregistered,
+    // Registration was not checked because `registration_policy` is `off`
+    Unchecked,
+}
+
+// Default size limits of `/hosts/stats` and `/hosts/stats/batch` payloads,
+// can be overridden with `host-stats` and `host-stats-batch` keys of Rocket's `limits` config
+const HOST_STATS_LIMIT: data::ByteUnit = data::ByteUnit::Kibibyte(256);
+const HOST_STATS_BATCH_LIMIT: data::ByteUnit = data::ByteUnit::Mebibyte(8);
+
+/// Reads whole body of a request, but no more than the limit configured under `limit_name`.
+/// Body compressed according to `Content-Encoding` header is decompressed, and the same limit
+/// applies to decompressed bytes, so that a small compressed body cannot expand without bound
+async fn read_body(
+    request: &Request<'_>,
+    data: Data<'_>,
+    limit_name: &str,
+    default_limit: data::ByteUnit,
+) -> Result<Vec<u8>, (Status, ApiError)> {
+    let limit = request.limits().get(limit_name).unwrap_or(default_limit);
+
+    let body = match data.open(limit).into_bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            return Err((
+                Status::BadRequest,
+                ApiError::BadRequest(Error400::Reason(Rejection::new(
+                    RejectReason::UnreadableBody,
+                    format!("Failed to read request body. Error: {:?}", e),
+                ))),
+            ))
+        }
+    };
+
+    if !body.is_complete() {
+        return Err(payload_too_large(limit));
+    }
+
+    decode_body(
+        request.headers().get_one("Content-Encoding"),
+        body.into_inner(),
+        limit,
+    )
+}
+
+/// Decompresses `body` encoded with gzip or zstd, failing once decompressed data exceeds `limit`.
+/// Signatures are verified over the decompressed bytes, so compression is transparent to hosts' keys
+pub fn decode_body(
+    encoding: Option<&str>,
+    body: Vec<u8>,
+    limit: data::ByteUnit,
+) -> Result<Vec<u8>, (Status, ApiError)> {
+    let malformed_encoding = |e: std::io::Error| {
+        (
+            Status::BadRequest,
+            ApiError::BadRequest(Error400::Reason(Rejection::new(
+                RejectReason::MalformedEncoding,
+                format!("Failed to decompress request body. Error: {:?}", e),
+            ))),
+        )
+    };
+
+    let encoding = encoding.map(|e| e.trim().to_ascii_lowercase());
+    let decoder: Box<dyn Read + '_> = match encoding.as_deref() {
+        None | Some("identity") => return Ok(body),
+        Some("gzip") | Some("x-gzip") => Box::new(GzDecoder::new(&body[..])),
+        Some("zstd") => Box::new(ZstdDecoder::new(&body[..]).map_err(malformed_encoding)?),
+        Some(other) => {
+            return Err((
+                Status::UnsupportedMediaType,
+                ApiError::UnsupportedEncoding(Error415::Reason(Rejection::new(
+                    RejectReason::UnsupportedEncoding,
+                    format!(
+                        "Content-Encoding {:?} is not supported, use gzip or zstd.",
+                        other
+                    ),
+                ))),
+            ))
+        }
+    };
+
+    // Read one byte over the limit to tell apart data of exactly `limit` length from a longer one
+    let mut decoded = vec![];
+    decoder
+        .take(limit.as_u64() + 1)
+        .read_to_end(&mut decoded)
+        .map_err(malformed_encoding)?;
+
+    if decoded.len() as u64 > limit.as_u64() {
+        return Err(payload_too_large(limit));
+    }
+
+    Ok(decoded)
+}
+
+fn payload_too_large(limit: data::ByteUnit) -> (Status, ApiError) {
+    (
+        Status::PayloadTooLarge,
+        ApiError::PayloadTooLarge(Error413::Reason(Rejection::new(
+            RejectReason::PayloadTooLarge,
+            format!("Request body exceeds the limit of {}", limit),
+        ))),
+    )
 }
 
 #[rocket::async_trait]
@@ -203,61 +737,389 @@ impl<'r> FromData<'r> for HostStats {
                 None => {
                     return Failure((
                         Status::Unauthorized,
-                        ApiError::MissingSignature(Error401::Message(
-                            "Host's hpos signature was not located in the request headers.",
-                        )),
+                        ApiError::MissingSignature(Error401::Reason(Rejection::new(
+                            RejectReason::MissingSignature,
+                            "Host's hpos signature was not located in the request headers.".into(),
+                        ))),
                     ))
                 }
             };
 
-            let byte_unit_data = data.open(data::ByteUnit::max_value());
-            let decoded_data = byte_unit_data.into_bytes().await.unwrap();
-            let host_stats: HostStats = match serde_json::from_slice(&decoded_data.value) {
-                Ok(hs) => hs,
-                Err(e) => {
-                    return Failure((
-                        Status::UnprocessableEntity,
-                        ApiError::InvalidPayload(Error400::Info(
-                            format!("Provided payload to `hosts/stats` does not match expected payload. Error: {:?}", e),
-                        )),
-                    ));
-                }
+            let format = match PayloadFormat::from_content_type(request.content_type()) {
+                Ok(format) => format,
+                Err(failure) => return Failure(failure),
+            };
+
+            let body = match read_body(request, data, "host-stats", HOST_STATS_LIMIT).await {
+                Ok(body) => body,
+                Err(failure) => return Failure(failure),
             };
 
-            let decoded_sig = match base64::decode(signature) {
-                Ok(ds) => ds,
+            return match verify_signed_payload(&body, format, &signature) {
+                Ok(host_stats) => Success(host_stats),
+                Err(failure) => Failure(failure),
+            };
+        }
+        Failure((
+            Status::BadRequest,
+            ApiError::BadRequest(Error400::Message(
+                "Made an unrecognized api call with the `HostStats` struct as parameters.",
+            )),
+        ))
+    }
+}
+
+// Serialization formats accepted for `HostStats` payload, selected by `Content-Type` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadFormat {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl PayloadFormat {
+    /// Json is assumed when `Content-Type` is not set, for compatibility with older hosts
+    pub fn from_content_type(
+        content_type: Option<&ContentType>,
+    ) -> Result<PayloadFormat, (Status, ApiError)> {
+        let content_type = match content_type {
+            None => return Ok(PayloadFormat::Json),
+            Some(content_type) => content_type,
+        };
+
+        match (content_type.top().as_str(), content_type.sub().as_str()) {
+            ("application", "json") => Ok(PayloadFormat::Json),
+            ("application", "cbor") => Ok(PayloadFormat::Cbor),
+            ("application", "msgpack") | ("application", "x-msgpack") => Ok(PayloadFormat::MsgPack),
+            _ => Err((
+                Status::UnsupportedMediaType,
+                ApiError::UnsupportedContentType(Error415::Reason(Rejection::new(
+                    RejectReason::UnsupportedContentType,
+                    format!(
+                        "Content-Type {} is not supported, use application/json, application/cbor or application/msgpack.",
+                        content_type
+                    ),
+                ))),
+            )),
+        }
+    }
+
+    pub fn deserialize(self, payload: &[u8]) -> Result<HostStats, String> {
+        match self {
+            PayloadFormat::Json => serde_json::from_slice(payload).map_err(|e| format!("{:?}", e)),
+            PayloadFormat::Cbor => {
+                ciborium::de::from_reader(payload).map_err(|e| format!("{:?}", e))
+            }
+            PayloadFormat::MsgPack => {
+                rmp_serde::from_slice(payload).map_err(|e| format!("{:?}", e))
+            }
+        }
+    }
+}
+
+/// Parses `payload` as `HostStats` and checks that `signature` (base64 encoded) is a valid
+/// ed25519 signature of `payload` made by the key encoded in its `holoportId`.
+/// Signature is always checked over the raw bytes of `payload`, whatever the `format`.
+/// Shared by single and batch submissions so that both are verified in exactly the same way.
+pub fn verify_signed_payload(
+    payload: &[u8],
+    format: PayloadFormat,
+    signature: &str,
+) -> Result<HostStats, (Status, ApiError)> {
+    let host_stats: HostStats = match format.deserialize(payload) {
+        Ok(hs) => hs,
+        Err(e) => {
+            return Err((
+                Status::UnprocessableEntity,
+                ApiError::InvalidPayload(Error400::Reason(Rejection::new(
+                    RejectReason::MalformedPayload,
+                    format!("Provided payload to `hosts/stats` does not match expected payload. Error: {:?}", e),
+                ))),
+            ));
+        }
+    };
+
+    if host_stats.schema_version.unwrap_or(1) > HOST_STATS_SCHEMA_VERSION {
+        return Err((
+            Status::UnprocessableEntity,
+            ApiError::InvalidPayload(Error400::Reason(Rejection::new(
+                RejectReason::UnsupportedSchemaVersion,
+                format!(
+                    "Provided payload has schemaVersion {:?}, latest supported version is {}.",
+                    host_stats.schema_version, HOST_STATS_SCHEMA_VERSION
+                ),
+            ))),
+        ));
+    }
+
+    let decoded_sig = match base64::decode(signature) {
+        Ok(ds) => ds,
+        Err(e) => {
+            return Err((
+                Status::UnprocessableEntity,
+                ApiError::InvalidSignature(Error401::Reason(Rejection::new(
+                    RejectReason::MalformedSignature,
+                    format!("Provided signature to `hosts/stats` does not have the expected encoding. Error: {:?}", e),
+                ))),
+            ));
+        }
+    };
+    let ed25519_sig = match decoded_sig.as_slice().try_into() {
+        Ok(sig_bytes) => Signature::from_bytes(sig_bytes),
+        Err(_) => {
+            return Err((
+                Status::UnprocessableEntity,
+                ApiError::InvalidSignature(Error401::Reason(Rejection::new(
+                    RejectReason::InvalidSignatureLength,
+                    format!(
+                        "Provided signature to `hosts/stats` is {} bytes long, expected {}.",
+                        decoded_sig.len(),
+                        ed25519_dalek::SIGNATURE_LENGTH
+                    ),
+                ))),
+            ));
+        }
+    };
+
+    let ed25519_pubkey = match db::decode_pubkey(&host_stats.holoport_id) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Err((Status::BadRequest, e)),
+    };
+
+    match ed25519_pubkey.verify_strict(payload, &ed25519_sig) {
+        Ok(_) => Ok(host_stats),
+        Err(_) => Err((
+            Status::Unauthorized,
+            ApiError::InvalidSignature(Error401::Reason(Rejection::new(
+                RejectReason::SignatureMismatch,
+                "Provided host signature does not match signature of signed payload.".into(),
+            ))),
+        )),
+    }
+}
+
+// Single element of input for /hosts/stats/batch endpoint.
+// `payload` is kept as raw json so that signature can be verified over the exact bytes host has signed
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SignedHostStats {
+    pub signature: String,
+    pub payload: Box<RawValue>,
+}
+
+// Input type for /hosts/stats/batch endpoint
+// Each report is verified independently, so a single bad report does not reject the whole batch
+pub struct HostStatsBatch {
+    pub reports: Vec<Result<HostStats, ApiError>>,
+}
+
+impl HostStatsBatch {
+    /// Verifies signature of each report on its own
+    pub fn verify(signed_reports: &[SignedHostStats]) -> Self {
+        let reports = signed_reports
+            .iter()
+            .map(|report| {
+                verify_signed_payload(
+                    report.payload.get().as_bytes(),
+                    PayloadFormat::Json,
+                    &report.signature,
+                )
+                .map_err(|(_, e)| e)
+            })
+            .collect();
+        HostStatsBatch { reports }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for HostStatsBatch {
+    type Error = ApiError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        if request.method() == Method::Post && request.uri().path() == "/hosts/stats/batch" {
+            let body =
+                match read_body(request, data, "host-stats-batch", HOST_STATS_BATCH_LIMIT).await {
+                    Ok(body) => body,
+                    Err(failure) => return Failure(failure),
+                };
+            let signed_reports: Vec<SignedHostStats> = match serde_json::from_slice(&body) {
+                Ok(reports) => reports,
                 Err(e) => {
                     return Failure((
                         Status::UnprocessableEntity,
-                        ApiError::InvalidSignature(Error401::Info(
-                            format!("Provided signature to `hosts/stats` does not have the expected encoding. Error: {:?}", e),
-                        )),
+                        ApiError::InvalidPayload(Error400::Reason(Rejection::new(
+                            RejectReason::MalformedPayload,
+                            format!(
+                                "Provided payload to `hosts/stats/batch` is not an array of signed reports. Error: {:?}",
+                                e
+                            ),
+                        ))),
                     ));
                 }
             };
-            let ed25519_sig = Signature::from_bytes(&decoded_sig.try_into().unwrap());
-
-            let ed25519_pubkey = db::decode_pubkey(&host_stats.holoport_id);
-
-            return match ed25519_pubkey.verify_strict(&decoded_data.value, &ed25519_sig) {
-                Ok(_) => Success(host_stats),
-                Err(_) => Failure((
-                    Status::Unauthorized,
-                    ApiError::InvalidSignature(Error401::Message(
-                        "Provided host signature does not match signature of signed payload.",
-                    )),
-                )),
-            };
+
+            return Success(HostStatsBatch::verify(&signed_reports));
         }
         Failure((
             Status::BadRequest,
             ApiError::BadRequest(Error400::Message(
-                "Made an unrecognized api call with the `HostStats` struct as parameters.",
+                "Made an unrecognized api call with the `HostStatsBatch` struct as parameters.",
             )),
         ))
     }
 }
 
+// Return type for /hosts/stats/batch endpoint, one per submitted report in submission order
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct BatchReportResult {
+    pub index: usize,
+    pub holoport_id: Option<String>,
+    pub accepted: bool,
+    pub reason: Option<RejectReason>,
+    pub error: Option<String>,
+}
+
+impl BatchReportResult {
+    /// Result of report at `index` of a batch, accepted if it is `Ok`
+    pub fn new(index: usize, report: Result<&HostStats, &ApiError>) -> Self {
+        match report {
+            Ok(stats) => BatchReportResult {
+                index,
+                holoport_id: Some(stats.holoport_id.clone()),
+                accepted: true,
+                reason: None,
+                error: None,
+            },
+            Err(e) => BatchReportResult {
+                index,
+                holoport_id: None,
+                accepted: false,
+                reason: e.reason(),
+                error: Some(e.message()),
+            },
+        }
+    }
+}
+
+// Page of a list, returned with total number of items in `X-Total-Count` header and cursor
+// of the next page in `X-Next-Cursor` header, so that body stays the same as without pagination
+pub struct Page<R> {
+    pub inner: R,
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
+impl<R> Page<R> {
+    pub fn map<T>(self, f: impl FnOnce(R) -> T) -> Page<T> {
+        Page {
+            inner: f(self.inner),
+            total: self.total,
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Page<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.inner.respond_to(request)?;
+        response.set_raw_header("X-Total-Count", self.total.to_string());
+        if let Some(cursor) = self.next_cursor {
+            response.set_raw_header("X-Next-Cursor", cursor);
+        }
+        Ok(response)
+    }
+}
+
+// Format of /hosts/list-available response, selected by `format` parameter or `Accept` header
+#[derive(FromFormField, Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    // Explicit `format` wins over the most preferred media type of `Accept`, json is the default
+    pub fn negotiate(format: Option<ExportFormat>, accept: Option<&Accept>) -> ExportFormat {
+        if let Some(format) = format {
+            return format;
+        }
+        match accept.map(|accept| accept.preferred().media_type()) {
+            Some(media_type) if *media_type == MediaType::CSV => ExportFormat::Csv,
+            Some(media_type) if media_type == ndjson_content_type().media_type() => {
+                ExportFormat::Ndjson
+            }
+            _ => ExportFormat::Json,
+        }
+    }
+}
+
+pub fn ndjson_content_type() -> ContentType {
+    ContentType::new("application", "x-ndjson")
+}
+
+// Hosts in one of `ExportFormat`s. Rows of csv and ndjson are serialized while the response is sent
+pub enum HostInfoExport {
+    Json(Json<Vec<HostInfo>>),
+    Csv(BoxStream<'static, String>),
+    Ndjson(BoxStream<'static, String>),
+}
+
+impl<'r> Responder<'r, 'r> for HostInfoExport {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        let (rows, content_type) = match self {
+            HostInfoExport::Json(hosts) => return hosts.respond_to(request),
+            HostInfoExport::Csv(rows) => (rows, ContentType::CSV),
+            HostInfoExport::Ndjson(rows) => (rows, ndjson_content_type()),
+        };
+        let mut response = TextStream(rows).respond_to(request)?;
+        response.set_header(content_type);
+        Ok(response)
+    }
+}
+
+// Single document of collection `host_statistics.holoport_status` as returned by /hosts/<holoport_id>/history
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct HostStatsRecord {
+    #[serde(flatten)]
+    pub stats: HostStats,
+    // Time of the last identical report, set only when reports are deduplicated
+    pub last_seen: Option<i64>,
+}
+
+// Return type for /hosts/<holoport_id> endpoint
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct HostDetail {
+    pub holoport_id: String,
+    // Latest record of the host in `holoport_status`
+    pub report: Option<HostStatsRecord>,
+    // ZeroTier member with the same IP as reported by the host or named after its holoport id
+    pub zerotier: Option<ZerotierMember>,
+    // Value precomputed in `performance_summary`
+    pub uptime: Option<f32>,
+    pub registration: HostRegistrationInfo,
+    // Errors that /hosts/list-available reports for the host
+    pub errors: Vec<String>,
+}
+
+// Registration of a host in `opsconsoledb.registrations`,
+// personal data of the registrant is None unless requested
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct HostRegistrationInfo {
+    pub status: RegistrationStatus,
+    pub given_names: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+}
+
 // Data schema of records retrieved from collection `host_statistics.latest_raw_snap`
 // Note - we are collecting only a subset of oryginal fields
 #[derive(Serialize, Deserialize, Clone, Default)]